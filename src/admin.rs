@@ -0,0 +1,243 @@
+use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::Path};
+
+use log::{debug, error, info};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    circuit_breaker::CircuitBreakers,
+    load_balancer::{BackendStats, LoadBalancer},
+    proxy_target::{DnsCacheEntry, SimpleCachingDnsResolver},
+};
+
+const USAGE: &str = "commands: dns list | dns show <target> | dns flush <target> | dns flush-all | breakers | backends\n";
+
+#[derive(Clone)]
+struct State {
+    dns_resolver: SimpleCachingDnsResolver,
+    circuit_breakers: CircuitBreakers,
+    // TCP mode only
+    balancer: Option<LoadBalancer>,
+}
+
+/// Control socket for operators, one command per line, e.g.
+/// `echo "dns flush example.com:443" | socat - UNIX-CONNECT:/run/http-tunnel.sock`.
+pub fn spawn(
+    path:&str,
+    dns_resolver:SimpleCachingDnsResolver,
+    circuit_breakers:CircuitBreakers,
+    balancer:Option<LoadBalancer>,
+) -> io::Result<()> {
+    // left over by a previous run, binding would fail
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            error!("Failed to remove stale admin socket {}: {}", path, e);
+            return Err(e);
+        }
+        _ => {}
+    }
+    let listener = bind(path).map_err(|e| {
+        error!("Failed to bind admin socket {}: {}", path, e);
+        e
+    })?;
+    info!("Admin socket listening on {}", path);
+
+    let state = State { dns_resolver, circuit_breakers, balancer };
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream,_)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &state).await {
+                            debug!("Admin connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept admin connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// It can flush caches, only our user may connect. The socket file gets the process umask,
+/// so it's bound in a private (0700) directory and moved into place once it's 0600.
+fn bind(path:&str) -> io::Result<UnixListener> {
+    let parent = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = tempfile::Builder::new().prefix(".admin-").permissions(Permissions::from_mode(0o700)).tempdir_in(parent)?;
+    let bound = private.path().join("admin.sock");
+    let listener = UnixListener::bind(&bound)?;
+    std::fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+    std::fs::rename(&bound, path)?;
+    Ok(listener)
+}
+
+async fn serve(stream:UnixStream, state:&State) -> io::Result<()> {
+    let (reader,mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        writer.write_all(execute(&line, state).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn execute(command:&str, state:&State) -> String {
+    let State { dns_resolver, circuit_breakers, balancer } = state;
+    match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        ["dns","list"] => {
+            let entries = dns_resolver.entries();
+            let mut reply: String = entries.iter().map(format_entry).collect();
+            reply.push_str(&format!("{} entries\n", entries.len()));
+            reply
+        }
+        ["dns","show",target] => match dns_resolver.entry(target) {
+            Some(entry) => format_entry(&entry),
+            None => format!("{} is not cached\n", target),
+        },
+        ["dns","flush",target] => match dns_resolver.flush(target) {
+            true => format!("flushed {}\n", target),
+            false => format!("{} is not cached\n", target),
+        },
+        ["dns","flush-all"] => format!("flushed {} entries\n", dns_resolver.flush_all()),
+        ["breakers"] => circuit_breakers
+            .stats()
+            .iter()
+            .map(|breaker| format!("{} is {:?}, {} recent failures, open for {:?}\n",
+                breaker.host, breaker.state, breaker.recent_failures, breaker.open_for))
+            .collect(),
+        ["backends"] => match balancer {
+            Some(balancer) => balancer.stats().iter().map(format_backend).collect(),
+            None => "no backends, not in TCP mode\n".to_string(),
+        },
+        _ => USAGE.to_string(),
+    }
+}
+
+fn format_backend(backend:&BackendStats) -> String {
+    let state = match (backend.healthy,backend.ejected) {
+        (false,_) => "unhealthy",
+        (true,true) => "ejected",
+        (true,false) => "up",
+    };
+    format!("{} is {}, weight {}, {} active, {} total, {} failed connections\n",
+        backend.address, state, backend.weight, backend.active_connections, backend.total_connections, backend.failed_connections)
+}
+
+fn format_entry(entry:&DnsCacheEntry) -> String {
+    format!("{} -> {:?}, expires in {:?}, idle for {:?}\n", entry.target, entry.addrs, entry.expires_in, entry.idle_for)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        configuration::{CircuitBreakerConfig, DnsBackendConfig, TcpBackend, TcpBalancerConfig},
+        dns_backend::DnsBackend,
+        proxy_target::DnsResolver,
+        tunnel::TunnelCtx,
+    };
+    use std::time::Duration;
+
+    fn resolver() -> SimpleCachingDnsResolver {
+        SimpleCachingDnsResolver::new(
+            DnsBackend::new(DnsBackendConfig::System, Duration::from_secs(1)).unwrap(),
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            10,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_dns_commands() {
+        let mut dns_resolver = resolver();
+        dns_resolver.resolve("127.0.0.1:80").await.unwrap();
+        dns_resolver.resolve("127.0.0.1:443").await.unwrap();
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("admin.sock");
+        spawn(path.to_str().unwrap(), dns_resolver.clone(), CircuitBreakers::new(None), None).unwrap();
+
+        // only our user may connect, and the private directory it was bound in is gone
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+
+        let (reader,mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        let mut replies = BufReader::new(reader).lines();
+        let mut command = async |command:&str| {
+            writer.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+            replies.next_line().await.unwrap().unwrap()
+        };
+
+        let state = State { dns_resolver: dns_resolver.clone(), circuit_breakers: CircuitBreakers::new(None), balancer: None };
+        let list = execute("dns list", &state);
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("127.0.0.1:443 -> [127.0.0.1:443], expires in"));
+        assert!(lines[1].starts_with("127.0.0.1:80 -> [127.0.0.1:80], expires in"));
+        assert_eq!(lines[2], "2 entries");
+
+        assert!(command("dns show 127.0.0.1:80").await.starts_with("127.0.0.1:80 -> [127.0.0.1:80], expires in"));
+        assert_eq!(command("dns flush 127.0.0.1:80").await, "flushed 127.0.0.1:80");
+        assert_eq!(command("dns show 127.0.0.1:80").await, "127.0.0.1:80 is not cached");
+        // the other entry is still there
+        assert!(dns_resolver.entry("127.0.0.1:443").is_some());
+        assert_eq!(command("dns flush-all").await, "flushed 1 entries");
+        assert_eq!(command("dns drop everything").await, USAGE.trim_end());
+    }
+
+    #[test]
+    fn test_breakers_command() {
+        let circuit_breakers = CircuitBreakers::new(Some(CircuitBreakerConfig {
+            failure_threshold: 2,
+            window: Duration::from_secs(10),
+            cool_down: Duration::from_secs(30),
+            half_open_probes: 1,
+        }));
+        let ctx = TunnelCtx::default();
+        let fail = |target:&str| circuit_breakers.acquire(target, &ctx).unwrap().record(Err(io::ErrorKind::ConnectionRefused));
+        fail("flaky.example:443");
+        fail("down.example:443");
+        fail("down.example:443");
+        let state = State { dns_resolver: resolver(), circuit_breakers: circuit_breakers.clone(), balancer: None };
+
+        let reply = execute("breakers", &state);
+        let mut lines: Vec<&str> = reply.lines().collect();
+        lines.sort();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("down.example is Open, 2 recent failures, open for Some("));
+        assert_eq!(lines[1], "flaky.example is Closed, 1 recent failures, open for None");
+
+        let state = State { circuit_breakers: CircuitBreakers::new(None), ..state };
+        assert_eq!(execute("breakers", &state), "");
+    }
+
+    #[test]
+    fn test_backends_command() {
+        let backends = vec![
+            TcpBackend { address: "10.0.0.1:80".to_string(), weight: 2 },
+            TcpBackend { address: "10.0.0.2:80".to_string(), weight: 1 },
+        ];
+        let balancer = LoadBalancer::new(backends, TcpBalancerConfig::default());
+        let connection = balancer.pick(None).unwrap();
+        connection.report_failure();
+        let mut state = State {
+            dns_resolver: resolver(),
+            circuit_breakers: CircuitBreakers::new(None),
+            balancer: Some(balancer),
+        };
+
+        assert_eq!(
+            execute("backends", &state),
+            "10.0.0.1:80 is up, weight 2, 1 active, 1 total, 1 failed connections\n\
+             10.0.0.2:80 is up, weight 1, 0 active, 0 total, 0 failed connections\n"
+        );
+        state.balancer = None;
+        assert_eq!(execute("backends", &state), "no backends, not in TCP mode\n");
+    }
+}
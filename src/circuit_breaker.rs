@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::io;
+
+use crate::{
+    configuration::CircuitBreakerConfig,
+    proxy_target::{Clock, SystemClock},
+    tunnel::TunnelCtx,
+};
+
+#[derive(Serialize,Clone,Copy,Debug,Eq,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Breaker of a target host, as exported.
+#[derive(Serialize,Clone,Debug)]
+pub struct BreakerStats {
+    pub host: String,
+    pub state: BreakerState,
+    // failures within the window
+    pub recent_failures: usize,
+    // until probes are let through, open breakers only
+    pub open_for: Option<Duration>,
+}
+
+struct Breaker {
+    state: BreakerState,
+    failures: VecDeque<Instant>,
+    open_until: Instant,
+    probes_in_flight: u32,
+}
+
+/// An attempt let through by [`CircuitBreakers::acquire`]. Dropped without
+/// [`BreakerPermit::record`], e.g. when a timeout cancels the connect, it counts
+/// as a failure, so a half-open probe slot is never lost.
+pub struct BreakerPermit {
+    breakers: Option<(CircuitBreakers,String)>,
+}
+
+impl BreakerPermit {
+    /// Outcome of the attempt.
+    pub fn record(mut self, result:Result<(),io::ErrorKind>) {
+        if let Some((breakers,target)) = self.breakers.take() {
+            breakers.record(&target, result);
+        }
+    }
+}
+
+impl Drop for BreakerPermit {
+    fn drop(&mut self) {
+        if let Some((breakers,target)) = self.breakers.take() {
+            debug!("Attempt to {} was cancelled, counting it as failed", target);
+            breakers.record(&target, Err(io::ErrorKind::TimedOut));
+        }
+    }
+}
+
+/// Circuit breakers by target host, shared by all the connectors.
+/// Only connection level failures count, a target refusing by policy is reachable.
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    config: Option<CircuitBreakerConfig>,
+    breakers: Arc<Mutex<HashMap<String,Breaker>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config:Option<CircuitBreakerConfig>) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config:Option<CircuitBreakerConfig>, clock:Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+            clock,
+        }
+    }
+
+    /// Lets an attempt to `target` through, fails fast with `ResourceBusy` while its breaker is open.
+    pub fn acquire(&self, target:&str, ctx:&TunnelCtx) -> io::Result<BreakerPermit> {
+        let Some(config) = &self.config else {
+            return Ok(BreakerPermit { breakers: None });
+        };
+        let now = self.clock.now();
+        let host = host_of(target);
+        let mut breakers = self.breakers.lock().expect("Bug: circuit breakers lock poisoned");
+        let Some(breaker) = breakers.get_mut(host) else {
+            return Ok(self.permit(target));
+        };
+
+        if breaker.state == BreakerState::Open && now >= breaker.open_until {
+            info!("Circuit breaker of {} is half-open, letting probes through", host);
+            breaker.state = BreakerState::HalfOpen;
+            breaker.probes_in_flight = 0;
+        }
+        match breaker.state {
+            BreakerState::Closed => Ok(self.permit(target)),
+            BreakerState::HalfOpen if breaker.probes_in_flight < config.half_open_probes => {
+                breaker.probes_in_flight += 1;
+                Ok(self.permit(target))
+            }
+            _ => {
+                error!("Circuit breaker of {} is open, not connecting to {}, CTX={}", host, target, ctx);
+                Err(io::Error::from(io::ErrorKind::ResourceBusy))
+            }
+        }
+    }
+
+    fn permit(&self, target:&str) -> BreakerPermit {
+        BreakerPermit { breakers: Some((self.clone(), target.to_string())) }
+    }
+
+    fn record(&self, target:&str, result:Result<(),io::ErrorKind>) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let now = self.clock.now();
+        let host = host_of(target);
+        let mut breakers = self.breakers.lock().expect("Bug: circuit breakers lock poisoned");
+
+        match result {
+            Ok(()) => {
+                if let Some(breaker) = breakers.get_mut(host) {
+                    if breaker.state == BreakerState::HalfOpen {
+                        info!("Circuit breaker of {} closed", host);
+                    }
+                    breaker.state = BreakerState::Closed;
+                    breaker.failures.clear();
+                }
+                // closed breakers without failures are the default, no need to keep them
+                breakers.retain(|_,breaker| breaker.state != BreakerState::Closed || !breaker.failures.is_empty());
+            }
+            Err(kind) if is_connection_failure(kind) => {
+                let breaker = breakers.entry(host.to_string()).or_insert_with(|| Breaker {
+                    state: BreakerState::Closed,
+                    failures: VecDeque::new(),
+                    open_until: now,
+                    probes_in_flight: 0,
+                });
+                breaker.failures.push_back(now);
+                while breaker.failures.front().is_some_and(|failure| now.duration_since(*failure) > config.window) {
+                    breaker.failures.pop_front();
+                }
+
+                let trips = match breaker.state {
+                    BreakerState::Closed => breaker.failures.len() >= config.failure_threshold as usize,
+                    BreakerState::HalfOpen => true,
+                    BreakerState::Open => false,
+                };
+                if trips {
+                    warn!("Circuit breaker of {} opened for {:?} after {} failures", host, config.cool_down, breaker.failures.len());
+                    breaker.state = BreakerState::Open;
+                    breaker.open_until = now + config.cool_down;
+                }
+            }
+            Err(_) => {
+                // says nothing about the host, the probe slot is free again
+                if let Some(breaker) = breakers.get_mut(host) {
+                    breaker.probes_in_flight = breaker.probes_in_flight.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Vec<BreakerStats> {
+        let now = self.clock.now();
+        let breakers = self.breakers.lock().expect("Bug: circuit breakers lock poisoned");
+        breakers
+            .iter()
+            .map(|(host,breaker)| BreakerStats {
+                host: host.clone(),
+                state: breaker.state,
+                recent_failures: breaker.failures.len(),
+                open_for: match breaker.state {
+                    BreakerState::Open => Some(breaker.open_until.saturating_duration_since(now)),
+                    _ => None,
+                },
+            })
+            .collect()
+    }
+}
+
+/// Errors worth another attempt, and that count against the host's breaker.
+pub fn is_connection_failure(kind:io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+fn host_of(target:&str) -> &str {
+    target.rsplit_once(':').map(|(host,_)| host).unwrap_or(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct MockClock {
+        now: Mutex<Instant>,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    impl MockClock {
+        fn advance(&self, by:Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    #[test]
+    fn test_breaker_opens_and_recovers() {
+        let clock = Arc::new(MockClock { now: Mutex::new(Instant::now()) });
+        let breakers = CircuitBreakers::with_clock(
+            Some(CircuitBreakerConfig {
+                failure_threshold: 3,
+                window: Duration::from_secs(10),
+                cool_down: Duration::from_secs(30),
+                half_open_probes: 1,
+            }),
+            clock.clone(),
+        );
+        let ctx = TunnelCtx::default();
+        let refused = Err(io::ErrorKind::ConnectionRefused);
+        // let through, with an outcome that says nothing about the host
+        let allowed = |target:&str| match breakers.acquire(target, &ctx) {
+            Ok(permit) => {
+                permit.record(Err(io::ErrorKind::PermissionDenied));
+                true
+            }
+            Err(_) => false,
+        };
+
+        // failures outside the window don't add up
+        breakers.record("flaky.example:443", refused);
+        breakers.record("flaky.example:443", refused);
+        clock.advance(Duration::from_secs(11));
+        breakers.record("flaky.example:8443", refused);
+        assert!(allowed("flaky.example:443"));
+
+        // policy answers don't count
+        breakers.record("flaky.example:443", Err(io::ErrorKind::PermissionDenied));
+        breakers.record("flaky.example:443", refused);
+        breakers.record("flaky.example:443", refused);
+        let denied = breakers.acquire("flaky.example:443", &ctx).err().unwrap();
+        assert_eq!(denied.kind(), io::ErrorKind::ResourceBusy);
+        assert!(allowed("healthy.example:443"));
+        assert_eq!(breakers.stats()[0].state, BreakerState::Open);
+
+        // a failed probe opens it again
+        clock.advance(Duration::from_secs(30));
+        let probe = breakers.acquire("flaky.example:443", &ctx).unwrap();
+        assert!(!allowed("flaky.example:443"));
+        probe.record(Err(io::ErrorKind::TimedOut));
+        assert!(!allowed("flaky.example:443"));
+
+        // a successful probe closes it
+        clock.advance(Duration::from_secs(30));
+        let probe = breakers.acquire("flaky.example:443", &ctx).unwrap();
+        probe.record(Ok(()));
+        assert!(allowed("flaky.example:443"));
+        assert!(breakers.stats().is_empty());
+    }
+
+    #[test]
+    fn test_dropped_probe_frees_its_slot() {
+        let clock = Arc::new(MockClock { now: Mutex::new(Instant::now()) });
+        let breakers = CircuitBreakers::with_clock(
+            Some(CircuitBreakerConfig {
+                failure_threshold: 1,
+                window: Duration::from_secs(10),
+                cool_down: Duration::from_secs(30),
+                half_open_probes: 1,
+            }),
+            clock.clone(),
+        );
+        let ctx = TunnelCtx::default();
+        breakers.acquire("flaky.example:443", &ctx).unwrap().record(Err(io::ErrorKind::ConnectionRefused));
+        assert_eq!(breakers.stats()[0].state, BreakerState::Open);
+
+        // cancelled before recording, e.g. by the connection timeout
+        clock.advance(Duration::from_secs(30));
+        let probe = breakers.acquire("flaky.example:443", &ctx).unwrap();
+        assert_eq!(breakers.stats()[0].state, BreakerState::HalfOpen);
+        drop(probe);
+        assert_eq!(breakers.stats()[0].state, BreakerState::Open);
+
+        // the next probe gets through and can close it
+        clock.advance(Duration::from_secs(30));
+        breakers.acquire("flaky.example:443", &ctx).unwrap().record(Ok(()));
+        assert!(breakers.stats().is_empty());
+    }
+}
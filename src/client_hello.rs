@@ -0,0 +1,217 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use log::debug;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+const RECORD_HEADER_SIZE: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_HOST_NAME: u8 = 0x00;
+// a ClientHello with post-quantum key shares takes a few KB, this is plenty
+const MAX_CLIENT_HELLO_SIZE: usize = 32768;
+
+/// Reads the TLS records carrying the ClientHello, returns them as read
+/// with the SNI host name, if the client sent one.
+pub async fn read_client_hello<S: AsyncRead + Unpin>(stream:&mut S) -> io::Result<(Vec<u8>,Option<String>)> {
+    let mut records = Vec::with_capacity(1024);
+    let mut handshake = Vec::with_capacity(1024);
+
+    // the handshake message may be fragmented over several records
+    while handshake.len() < 4 || handshake.len() < 4 + u24(&handshake[1..4]) {
+        let start = records.len();
+        records.resize(start + RECORD_HEADER_SIZE, 0);
+        stream.read_exact(&mut records[start..]).await?;
+        if records[start] != CONTENT_TYPE_HANDSHAKE {
+            debug!("Not a TLS handshake record: {:#04x}", records[start]);
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let length = u16::from_be_bytes([records[start + 3],records[start + 4]]) as usize;
+        if records.len() + length > MAX_CLIENT_HELLO_SIZE {
+            debug!("ClientHello is larger than {} bytes", MAX_CLIENT_HELLO_SIZE);
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        let payload = records.len();
+        records.resize(payload + length, 0);
+        stream.read_exact(&mut records[payload..]).await?;
+        handshake.extend_from_slice(&records[payload..]);
+    }
+
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        debug!("First handshake message is not a ClientHello: {}", handshake[0]);
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    let server_name = parse_server_name(&handshake[4..4 + u24(&handshake[1..4])])
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    Ok((records,server_name))
+}
+
+/// The `host_name` of the server_name extension (RFC 6066), `None` inside means no SNI.
+fn parse_server_name(client_hello:&[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(client_hello);
+    reader.take(2 + 32)?; // legacy_version, random
+    reader.vector(1)?; // legacy_session_id
+    reader.vector(2)?; // cipher_suites
+    reader.vector(1)?; // legacy_compression_methods
+    if reader.0.is_empty() {
+        return Some(None);
+    }
+
+    let mut extensions = Reader(reader.vector(2)?);
+    while !extensions.0.is_empty() {
+        let extension_type = u16::from_be_bytes(extensions.take(2)?.try_into().ok()?);
+        let data = extensions.vector(2)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader(Reader(data).vector(2)?);
+        while !names.0.is_empty() {
+            let name_type = names.take(1)?[0];
+            let name = names.vector(2)?;
+            if name_type == SERVER_NAME_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.trim_end_matches('.').to_ascii_lowercase()));
+            }
+        }
+    }
+    Some(None)
+}
+
+fn u24(bytes:&[u8]) -> usize {
+    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len:usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken,rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    // length prefixed by `len_size` bytes
+    fn vector(&mut self, len_size:usize) -> Option<&'a [u8]> {
+        let len = self.take(len_size)?.iter().fold(0usize, |len,b| len << 8 | *b as usize);
+        self.take(len)
+    }
+}
+
+/// `inner` with bytes already read from it put back in front.
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix:Vec<u8>, inner:S) -> Self {
+        Self { prefix, position: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(self:Pin<&mut Self>, cx:&mut Context<'_>, buf:&mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position < this.prefix.len() {
+            let remaining = &this.prefix[this.position..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            this.position += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(self:Pin<&mut Self>, cx:&mut Context<'_>, buf:&[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self:Pin<&mut Self>, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self:Pin<&mut Self>, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// ClientHello with a single extension, split in records of `record_size`.
+    fn client_hello(server_name:Option<&str>, record_size:usize) -> Vec<u8> {
+        let mut body = vec![0x03,0x03];
+        body.extend([0u8;32]);
+        body.extend([0x00, 0x00,0x02,0x13,0x01, 0x01,0x00]);
+        let mut extensions = vec![];
+        if let Some(name) = server_name {
+            let mut list = vec![SERVER_NAME_HOST_NAME];
+            list.extend((name.len() as u16).to_be_bytes());
+            list.extend(name.as_bytes());
+            let mut data = (list.len() as u16).to_be_bytes().to_vec();
+            data.extend(list);
+            extensions.extend([0x00,0x00]);
+            extensions.extend((data.len() as u16).to_be_bytes());
+            extensions.extend(data);
+        }
+        // an unrelated extension, supported_versions
+        extensions.extend([0x00,0x2b, 0x00,0x03, 0x02,0x03,0x04]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        handshake
+            .chunks(record_size)
+            .flat_map(|fragment| {
+                let mut record = vec![CONTENT_TYPE_HANDSHAKE,0x03,0x01];
+                record.extend((fragment.len() as u16).to_be_bytes());
+                record.extend(fragment);
+                record
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let hello = client_hello(Some("Team-A.Example.com."), 1000);
+        let mut stream = [hello.clone(), b"after".to_vec()].concat();
+        let (read,server_name) = read_client_hello(&mut stream.as_slice()).await.unwrap();
+        assert_eq!(read, hello);
+        assert_eq!(server_name.as_deref(), Some("team-a.example.com"));
+
+        // fragmented over several records
+        let hello = client_hello(Some("team-b.example.com"), 16);
+        let (read,server_name) = read_client_hello(&mut hello.as_slice()).await.unwrap();
+        assert_eq!(read.len(), hello.len());
+        assert_eq!(server_name.as_deref(), Some("team-b.example.com"));
+
+        let hello = client_hello(None, 1000);
+        assert_eq!(read_client_hello(&mut hello.as_slice()).await.unwrap().1, None);
+
+        stream = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+        assert!(read_client_hello(&mut stream.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefixed_stream() {
+        let mut stream = PrefixedStream::new(b"hello ".to_vec(), &b"world"[..]);
+        let mut read = String::new();
+        stream.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "hello world");
+    }
+}
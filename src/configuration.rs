@@ -1,13 +1,16 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::fs::File;
 use std::time::Duration;
 use std::io::{Error, ErrorKind, Read}; 
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub mod relay;
 
 use crate::relay::{RelayPolicy,NO_BANDWITH_LIMIT,NO_TIMEOUT};
 use clap::error::Error as ClapError;
 use log::{error,info};
-use native_tls::Identity;
 use serde::Deserialize;
 use clap::Parser;
 use clap::Subcommand;
@@ -16,19 +19,428 @@ use serde::Serialize;
 use tokio::sync::oneshot::error;
 use derive_builder::Builder;
 use regex::Regex;
+use base64::{engine::general_purpose::STANDARD, Engine};
 
 use tokio::io;
 
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+compile_error!("enable a TLS backend: the `openssl` (default) or the `rustls` feature");
+
 #[derive(Deserialize,Clone)]
 pub struct TargetConnectionConfig {
         #[serde(with = "humantime_serde")]
     pub dns_cache_ttl:Duration,
+    // how long a failed lookup is remembered
+    #[serde(default = "default_dns_negative_cache_ttl", with = "humantime_serde")]
+    pub dns_negative_cache_ttl:Duration,
+    #[serde(default = "default_dns_cache_max_entries")]
+    pub dns_cache_max_entries:usize,
+    // entries used during the last TTL are re-resolved this long before they expire
+    #[serde(default, with = "humantime_serde")]
+    pub dns_refresh_ahead: Option<Duration>,
+    // where lookups go, the system resolver unless configured
+    #[serde(default)]
+    pub dns_backend: DnsBackendConfig,
+    // per query and nameserver, system lookups are not bounded by it
+    #[serde(default = "default_dns_query_timeout", with = "humantime_serde")]
+    pub dns_query_timeout: Duration,
         #[serde(with = "serde_regex")]
     pub allowed_targets: Regex,
         #[serde(with = "humantime_serde")]
     pub connection_timeout: Duration,
     pub relay_policy:RelayPolicy,
+    // PROXY protocol header for every target, e.g. in TCP mode
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    // pinned addresses by host name, like /etc/hosts, checked before the DNS resolver
+    #[serde(default)]
+    pub hosts: HashMap<String,Vec<IpAddr>>,
+    // first matching rule sends the requested target elsewhere
+    #[serde(default)]
+    pub rewrites: Vec<RewriteRule>,
+    // which address families outbound connections use
+    #[serde(default)]
+    pub ip_family: IpFamilyPolicy,
+    // delay between staggered connection attempts to the resolved addresses
+    #[serde(default = "default_connection_attempt_delay", with = "humantime_serde")]
+    pub connection_attempt_delay: Duration,
+    // local address or interface of outbound connections, the OS picks without it
+    #[serde(default)]
+    pub source: Option<SourceConfig>,
+    // by authenticated user, takes precedence over routes
+    #[serde(default)]
+    pub tenant_sources: HashMap<String,SourceConfig>,
+    // by authenticated user, instead of `allowed_targets`
+    #[serde(default, deserialize_with = "deserialize_regex_map")]
+    pub tenant_allowed_targets: HashMap<String,Regex>,
+    // by the SNI clients of the HTTPS listener asked for, used for users without a tenant ACL
+    #[serde(default, deserialize_with = "deserialize_regex_map")]
+    pub server_name_allowed_targets: HashMap<String,Regex>,
+    #[serde(default)]
+    pub socket_options: SocketOptions,
+    // extra attempts after a connection level failure, all within `connection_timeout`
+    #[serde(default)]
+    pub connect_retries: u32,
+    // first backoff, doubled for every retry, the actual sleep is drawn below it
+    #[serde(default = "default_retry_backoff", with = "humantime_serde")]
+    pub retry_backoff: Duration,
+    // per target host, disabled without it
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // TLS towards every target, e.g. in TCP mode
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+
+}
+
+fn default_dns_negative_cache_ttl() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_dns_cache_max_entries() -> usize {
+    10_000
+}
+
+fn default_dns_query_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_retry_backoff() -> Duration {
+    Duration::from_millis(100)
+}
+
+// RFC 8305 recommended "Connection Attempt Delay"
+fn default_connection_attempt_delay() -> Duration {
+    Duration::from_millis(250)
+}
+
+impl TargetConnectionConfig {
+    /// First route whose `targets` matches, routes are checked in config order.
+    pub fn route_for(&self, target:&str) -> Option<&RouteConfig> {
+        self.routes.iter().find(|route| route.targets.is_match(target))
+    }
+
+    pub fn proxy_protocol_for(&self, target:&str) -> Option<&ProxyProtocolConfig> {
+        self.route_for(target)
+            .and_then(|route| route.proxy_protocol.as_ref())
+            .or(self.proxy_protocol.as_ref())
+    }
+
+    /// Parents to tunnel `target` through, empty for a direct connection.
+    pub fn parent_proxies_for(&self, target:&str) -> &[ParentProxyConfig] {
+        self.route_for(target)
+            .map(|route| route.parent_proxies.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn upstream_tls_for(&self, target:&str) -> Option<&UpstreamTlsConfig> {
+        self.route_for(target)
+            .and_then(|route| route.upstream_tls.as_ref())
+            .or(self.upstream_tls.as_ref())
+    }
+
+    pub fn ip_family_for(&self, target:&str) -> IpFamilyPolicy {
+        self.route_for(target)
+            .and_then(|route| route.ip_family)
+            .unwrap_or(self.ip_family)
+    }
+
+    /// Where connections to `target` on behalf of `user` come from:
+    /// the user's tenant source, the route's, then the default one.
+    pub fn source_for(&self, target:&str, user:Option<&str>) -> Option<&SourceConfig> {
+        user.and_then(|user| self.tenant_sources.get(user))
+            .or_else(|| self.route_for(target).and_then(|route| route.source.as_ref()))
+            .or(self.source.as_ref())
+    }
+
+    /// The ACL of the user's tenant, of the listener name the client used, then the default one.
+    pub fn allowed_targets_for(&self, user:Option<&str>, server_name:Option<&str>) -> &Regex {
+        user.and_then(|user| self.tenant_allowed_targets.get(user))
+            .or_else(|| server_name.and_then(|server_name| self.server_name_allowed_targets.get(server_name)))
+            .unwrap_or(&self.allowed_targets)
+    }
+
+    pub fn socket_options_for(&self, target:&str) -> &SocketOptions {
+        self.route_for(target)
+            .and_then(|route| route.socket_options.as_ref())
+            .unwrap_or(&self.socket_options)
+    }
+
+    /// The `host:port` to connect to instead of `target`, if a rewrite rule matches.
+    pub fn rewrite_target(&self, target:&str) -> Option<String> {
+        self.rewrites
+            .iter()
+            .find(|rule| rule.target.is_match(target))
+            .map(|rule| rule.target.replace(target, rule.rewrite_to.as_str()).into_owned())
+    }
+
+    /// Pinned addresses of `target` (`host:port`), host names are case insensitive.
+    pub fn static_addrs(&self, target:&str) -> Option<Vec<SocketAddr>> {
+        let (host,port) = target.rsplit_once(':')?;
+        let port = port.parse::<u16>().ok()?;
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+
+        self.hosts
+            .iter()
+            .find(|(name,_)| name.trim_end_matches('.').eq_ignore_ascii_case(host.trim_end_matches('.')))
+            .map(|(_,ips)| ips.iter().map(|ip| SocketAddr::new(*ip,port)).collect())
+    }
+}
+
+/// Opens after `failure_threshold` connection failures within `window`, fails fast
+/// for `cool_down`, then lets `half_open_probes` attempts through to decide.
+#[derive(Deserialize,Clone,Debug)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    #[serde(with = "humantime_serde")]
+    pub cool_down: Duration,
+    #[serde(default = "default_half_open_probes")]
+    pub half_open_probes: u32,
+}
+
+fn default_half_open_probes() -> u32 {
+    1
+}
+
+/// Split-horizon rewrite, e.g. `^api\.partner\.com:443$` to `partner-gw.internal:8443`.
+/// `rewrite_to` may refer to capture groups of `target` as `$1`, `$name`.
+#[derive(Deserialize,Clone)]
+pub struct RewriteRule {
+    #[serde(with = "serde_regex")]
+    pub target: Regex,
+    pub rewrite_to: String,
+}
+
+/// DNS backend, nameservers are tried in order and never fall back
+/// to the system resolver, so lookups only reach the configured servers.
+#[derive(Deserialize,Clone,Debug,Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DnsBackendConfig {
+    #[default]
+    System,
+    // truncated answers are retried over TCP on the same nameserver
+    Udp { nameservers: Vec<SocketAddr> },
+    Tcp { nameservers: Vec<SocketAddr> },
+    // DNS-over-TLS (RFC 7858), `server_name` is verified against the certificate
+    Tls { nameservers: Vec<SocketAddr>, server_name: String },
+    // DNS-over-HTTPS (RFC 8484), e.g. https://dns.example.org/dns-query
+    // `addresses` reach the server without a lookup, the URL host must be an IP otherwise
+    Https {
+        url: String,
+        #[serde(default)]
+        addresses: Vec<SocketAddr>,
+    },
+}
+
+#[derive(Deserialize,Clone)]
+pub struct RouteConfig {
+    #[serde(with = "serde_regex")]
+    pub targets: Regex,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    #[serde(default)]
+    pub ip_family: Option<IpFamilyPolicy>,
+    // tried in order until one of them establishes the tunnel
+    #[serde(default)]
+    pub parent_proxies: Vec<ParentProxyConfig>,
+    #[serde(default)]
+    pub source: Option<SourceConfig>,
+    // replaces the default socket options as a whole
+    #[serde(default)]
+    pub socket_options: Option<SocketOptions>,
+    #[serde(default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+}
+
+/// TLS originated by the proxy, so plain TCP clients can reach TLS-only targets.
+/// The files are read once, for the first connection using them.
+#[derive(Deserialize,Clone,Debug)]
+pub struct UpstreamTlsConfig {
+    // SNI and the name verified, defaults to the target host
+    #[serde(default)]
+    pub server_name: Option<String>,
+    // PEM bundle trusted instead of the system roots
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    // PEM chain and PKCS#8 key presented for mTLS, both or neither
+    #[serde(default)]
+    pub client_cert_file: Option<String>,
+    #[serde(default)]
+    pub client_key_file: Option<String>,
+}
+
+/// Socket tuning, options left unset keep the OS defaults.
+/// `mark`, `fast_open` and `congestion_control` are Linux only.
+#[derive(Deserialize,Clone,Debug)]
+pub struct SocketOptions {
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    #[serde(default)]
+    pub keepalive: Option<KeepaliveConfig>,
+    // SO_MARK for policy routing, needs CAP_NET_ADMIN
+    #[serde(default)]
+    pub mark: Option<u32>,
+    // DSCP code point (0-63) for IP_TOS or the IPv6 traffic class
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    // TCP_FASTOPEN_CONNECT on outbound sockets, a TCP_FASTOPEN queue on listeners
+    #[serde(default)]
+    pub fast_open: bool,
+    // TCP_CONGESTION, e.g. bbr, the algorithm has to be available to unprivileged users
+    #[serde(default)]
+    pub congestion_control: Option<String>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: default_nodelay(),
+            keepalive: None,
+            mark: None,
+            dscp: None,
+            fast_open: false,
+            congestion_control: None,
+        }
+    }
+}
+
+fn default_nodelay() -> bool {
+    true
+}
+
+/// TCP keepalive, probes start after `time` idle and a peer missing `retries` probes is dead.
+#[derive(Deserialize,Clone,Debug)]
+pub struct KeepaliveConfig {
+    #[serde(with = "humantime_serde")]
+    pub time: Duration,
+    #[serde(default, with = "humantime_serde")]
+    pub interval: Option<Duration>,
+    #[serde(default)]
+    pub retries: Option<u32>,
+}
+
+/// Local side of outbound sockets, for egress rules keyed on the source.
+#[derive(Deserialize,Clone,Debug,Default)]
+pub struct SourceConfig {
+    // pool rotated per connection, targets of a family without an address are unreachable
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    // SO_BINDTODEVICE, Linux only and needs CAP_NET_RAW
+    #[serde(default)]
+    pub interface: Option<String>,
+    // shared by the clones of the config, so the rotation spans connections
+    #[serde(skip)]
+    pub(crate) next: Arc<AtomicUsize>,
+}
 
+impl SourceConfig {
+    /// Whether a source address can reach `target`, always true without a pool.
+    pub fn allows(&self, target:&SocketAddr) -> bool {
+        self.addresses.is_empty() || self.addresses.iter().any(|addr| addr.is_ipv4() == target.is_ipv4())
+    }
+
+    /// Next pool address of `target`'s family, `None` lets the OS choose.
+    pub fn next_address(&self, target:&SocketAddr) -> Option<IpAddr> {
+        let candidates: Vec<IpAddr> = self.addresses
+            .iter()
+            .filter(|addr| addr.is_ipv4() == target.is_ipv4())
+            .copied()
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()])
+    }
+}
+
+/// Upstream HTTP CONNECT or SOCKS5 proxy.
+#[derive(Deserialize,Clone)]
+pub struct ParentProxyConfig {
+    // host:port
+    pub address: String,
+    #[serde(default)]
+    pub protocol: ParentProxyProtocol,
+    // SOCKS5 only: let the parent resolve the target, otherwise it gets our addresses
+    #[serde(default = "default_remote_dns")]
+    pub remote_dns: bool,
+    #[serde(default)]
+    pub tls: bool,
+    // defaults to the host of `address`
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+    // Basic credentials, sent only when both are set
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // per parent, keep it below `connection_timeout` to leave time for failover
+    #[serde(default = "default_parent_connect_timeout", with = "humantime_serde")]
+    pub connect_timeout: Duration,
+}
+
+#[derive(Deserialize,Clone,Copy,Debug,Default,Eq,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParentProxyProtocol {
+    #[default]
+    Http,
+    Socks5,
+}
+
+fn default_remote_dns() -> bool {
+    true
+}
+
+fn default_parent_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[derive(Deserialize,Clone,Copy,Debug,Default,Eq,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamilyPolicy {
+    // both families, connection attempts alternate between them starting with IPv6 (RFC 8305)
+    #[default]
+    Any,
+    Ipv4Only,
+    Ipv6Only,
+    // attempts still alternate, but start with the preferred family
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl IpFamilyPolicy {
+    /// Filters and orders resolved addresses, the order within a family is kept.
+    pub fn apply(&self, mut addrs:Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            IpFamilyPolicy::Any => {}
+            IpFamilyPolicy::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+            IpFamilyPolicy::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+            IpFamilyPolicy::PreferIpv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            IpFamilyPolicy::PreferIpv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+        }
+        addrs
+    }
+}
+
+#[derive(Deserialize,Clone,Copy,Debug,Eq,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct ProxyProtocolConfig {
+    pub version: ProxyProtocolVersion,
+    // v2 only: TLV with the authenticated user
+    #[serde(default)]
+    pub send_user: bool,
+    // v2 only: PP2_TYPE_UNIQUE_ID TLV with the TunnelCtx id
+    #[serde(default)]
+    pub send_tunnel_id: bool,
 }
 
 #[derive(Args,Debug)]
@@ -48,7 +460,9 @@ struct HttpOptions{
 #[command(author = "Billy", version="0.1.0", long_about = None)]
 #[command(propagate_version = true)]
 struct TcpOptions{
-    destination:String,
+    // one or more `host:port`, optionally weighted as `host:port=weight`
+    #[arg(required = true, num_args = 1..)]
+    destination:Vec<String>,
 }
 
 #[derive(Args,Debug)]
@@ -56,12 +470,39 @@ struct TcpOptions{
 #[command(author = "Billy", version="0.1.0", long_about = None)]
 #[command(propagate_version = true)]
 struct HttpsOptions {
+    // PKCS#12 bundle, or a PEM chain `--cert` and its `--key`
+    #[arg(long, required_unless_present = "cert", conflicts_with = "cert")]
+    pk:Option<String>,
 
-    #[arg(long)]
-    pk:String,
+    #[arg(long, requires = "pk")]
+    password:Option<String>,
+
+    #[arg(long, requires = "key")]
+    cert:Option<String>,
+
+    // PKCS#8, PKCS#1 (RSA) or SEC1 (ECDSA), unencrypted
+    #[arg(long, requires = "cert")]
+    key:Option<String>,
+}
 
+#[derive(Args,Debug)]
+#[command(about =" Run the tunnel in SOCKS5 mode", long_about = None)]
+#[command(author = "Billy", version="0.1.0", long_about = None)]
+#[command(propagate_version = true)]
+struct Socks5Options {
+    // file with `user:password` lines, enables RFC 1929 authentication
     #[arg(long)]
-    password:String,
+    users:Option<String>,
+}
+
+#[derive(Args,Debug)]
+#[command(about =" Run the tunnel in TLS passthrough mode", long_about = None)]
+#[command(author = "Billy", version="0.1.0", long_about = None)]
+#[command(propagate_version = true)]
+struct TlsPassthroughOptions {
+    // port of the SNI host connections are forwarded to
+    #[arg(long, default_value_t = 443)]
+    port:u16,
 }
 
 #[derive(Subcommand,Debug)]
@@ -69,6 +510,8 @@ enum Command {
     Http(HttpOptions),
     Https(HttpsOptions),
     Tcp(TcpOptions),
+    Socks5(Socks5Options),
+    TlsPassthrough(TlsPassthroughOptions),
 }
 
 
@@ -91,8 +534,209 @@ struct Cli {
 #[derive(Clone)]
 pub enum ProxyMode {
     Http,
-    Https(Identity),
-    Tcp(String)
+    Https(TlsIdentities),
+    // backends to balance across
+    Tcp(Vec<TcpBackend>),
+    // username -> password, no authentication when empty
+    Socks5(HashMap<String,String>),
+    // raw TLS forwarded to `<SNI>:<port>` without terminating it
+    TlsPassthrough(u16),
+}
+
+/// Certificates of the HTTPS listener, picked by the SNI of the ClientHello.
+#[derive(Clone)]
+pub struct TlsIdentities {
+    // without SNI or when no server name matches
+    pub default: TlsIdentity,
+    // in config order, names may be `*.example.com` wildcards
+    pub by_server_name: Vec<(Vec<String>,TlsIdentity)>,
+    // to load them again when the files change
+    pub source: TlsIdentitiesSource,
+}
+
+/// PEM certificate chain, leaf first, and its unencrypted PKCS#8 key.
+#[derive(Clone)]
+pub struct TlsIdentity {
+    pub chain: String,
+    pub key: String,
+}
+
+/// Files of the default certificate, from the command line.
+#[derive(Clone,Debug)]
+pub enum IdentitySource {
+    Pkcs12 { file: String, password: String },
+    Pem { cert: String, key: String },
+}
+
+impl IdentitySource {
+    pub fn load(&self) -> io::Result<TlsIdentity> {
+        match self {
+            IdentitySource::Pkcs12 { file, password } => ProxyConfiguration::tls_identity_from_file(file, password),
+            IdentitySource::Pem { cert, key } => ProxyConfiguration::tls_identity_from_pem(cert, key),
+        }
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct TlsIdentitiesSource {
+    pub default: IdentitySource,
+    pub tls: TlsListenerConfig,
+}
+
+impl TlsIdentitiesSource {
+    pub fn load(&self) -> io::Result<TlsIdentities> {
+        let by_server_name = self.tls.certificates
+            .iter()
+            .map(|certificate| {
+                info!("Serving {} for {:?}", certificate.cert, certificate.server_names);
+                let identity = ProxyConfiguration::tls_identity_from_pem(&certificate.cert, &certificate.key)?;
+                Ok((certificate.server_names.clone(),identity))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(TlsIdentities { default: self.default.load()?, by_server_name, source: self.clone() })
+    }
+
+    /// Every certificate and key file, in no particular order.
+    pub fn files(&self) -> Vec<&str> {
+        let mut files = match &self.default {
+            IdentitySource::Pkcs12 { file, .. } => vec![file.as_str()],
+            IdentitySource::Pem { cert, key } => vec![cert.as_str(),key.as_str()],
+        };
+        for certificate in &self.tls.certificates {
+            files.push(&certificate.cert);
+            files.push(&certificate.key);
+        }
+        if let Some(client_auth) = &self.tls.client_auth {
+            files.push(&client_auth.ca_file);
+            files.extend(client_auth.crl_files.iter().map(String::as_str));
+        }
+        files
+    }
+}
+
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct TcpBackend {
+    pub address: String,
+    // only used by the weighted strategy
+    pub weight: u32,
+}
+
+/// How TCP mode spreads connections over its backends.
+#[derive(Deserialize,Clone,Debug)]
+pub struct TcpBalancerConfig {
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    // active checks, backends are always considered healthy without them
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    // passive checks: consecutive connect failures before a backend is ejected
+    #[serde(default = "default_max_connect_failures")]
+    pub max_connect_failures: u32,
+    #[serde(default = "default_ejection_time", with = "humantime_serde")]
+    pub ejection_time: Duration,
+    // clients come through a load balancer sending a PROXY header, its source is
+    // the client address for hashing, logs and the PROXY header we send
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+}
+
+impl Default for TcpBalancerConfig {
+    fn default() -> Self {
+        Self {
+            strategy: BalanceStrategy::RoundRobin,
+            health_check: None,
+            max_connect_failures: default_max_connect_failures(),
+            ejection_time: default_ejection_time(),
+            accept_proxy_protocol: false,
+        }
+    }
+}
+
+#[derive(Deserialize,Clone,Copy,Debug,Default,Eq,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Weighted,
+    // sticky by client IP, weights scale the share of the ring
+    ConsistentHash,
+}
+
+/// TCP connect probes, a backend changes state after `*_threshold` probes in a row.
+#[derive(Deserialize,Clone,Debug)]
+pub struct HealthCheckConfig {
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(default = "default_health_threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_health_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_max_connect_failures() -> u32 {
+    3
+}
+
+fn default_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+// serde_regex has no maps
+fn deserialize_regex_map<'de, D: serde::Deserializer<'de>>(deserializer:D) -> Result<HashMap<String,Regex>,D::Error> {
+    HashMap::<String,String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key,regex)| Regex::new(&regex).map(|regex| (key,regex)).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn default_health_threshold() -> u32 {
+    2
+}
+
+/// TLS of the HTTPS listener, beyond the default certificate of the command line.
+#[derive(Deserialize,Clone,Debug,Default)]
+pub struct TlsListenerConfig {
+    #[serde(default)]
+    pub certificates: Vec<SniCertificateConfig>,
+    // clients authenticate with certificates of our CA, off when absent
+    #[serde(default)]
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+/// PEM chain and key served to clients asking for one of `server_names`.
+#[derive(Deserialize,Clone,Debug)]
+pub struct SniCertificateConfig {
+    pub server_names: Vec<String>,
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct ClientAuthConfig {
+    // otherwise clients without a certificate get in anonymously
+    #[serde(default)]
+    pub required: bool,
+    // PEM bundle of the CAs issuing client certificates
+    pub ca_file: String,
+    // PEM CRLs, checked for the client certificate itself
+    #[serde(default)]
+    pub crl_files: Vec<String>,
+    #[serde(default)]
+    pub identity: ClientIdentityField,
+}
+
+/// Part of the client certificate that becomes the tunnel user,
+/// the other one is used when it is missing.
+#[derive(Deserialize,Clone,Copy,Debug,Default,Eq,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdentityField {
+    #[default]
+    CommonName,
+    // first URI, DNS or email SAN
+    SubjectAltName,
 }
 
 #[derive(Deserialize,Clone)]
@@ -100,6 +744,9 @@ pub struct ClientConnectionConfig {
     #[serde(with="humantime_serde")]
     pub initiation_timeout:Duration,
     pub relay_policy: RelayPolicy,
+    // applied to accepted connections, `fast_open` to the listener
+    #[serde(default)]
+    pub socket_options: SocketOptions,
 }
 
 
@@ -107,6 +754,13 @@ pub struct ClientConnectionConfig {
 pub struct TunnelConfig {
     pub client_connection: ClientConnectionConfig,
     pub target_connection: TargetConnectionConfig,
+    #[serde(default)]
+    pub tcp_balancer: TcpBalancerConfig,
+    #[serde(default)]
+    pub tls: TlsListenerConfig,
+    // Unix socket for operator commands, e.g. inspecting and flushing DNS entries
+    #[serde(default)]
+    pub admin_socket: Option<String>,
 
 }
 
@@ -119,19 +773,43 @@ impl Default for TunnelConfig {
                     idle_timeout:NO_TIMEOUT,
                     min_rate_bpm:NO_BANDWITH_LIMIT,
                     max_rate_bps:NO_BANDWITH_LIMIT,
-                }
+                },
+                socket_options: SocketOptions::default(),
             },
             target_connection: TargetConnectionConfig {
                 dns_cache_ttl: NO_TIMEOUT,
+                dns_negative_cache_ttl: default_dns_negative_cache_ttl(),
+                dns_cache_max_entries: default_dns_cache_max_entries(),
+                dns_refresh_ahead: None,
+                dns_backend: DnsBackendConfig::System,
+                dns_query_timeout: default_dns_query_timeout(),
                 allowed_targets: Regex::new(".*").expect("Bug: bad default regexp"),
                 connection_timeout: NO_TIMEOUT,
                 relay_policy: RelayPolicy {
                     idle_timeout:NO_TIMEOUT,
                     min_rate_bpm:0,
                     max_rate_bps:NO_BANDWITH_LIMIT,
-                }
+                },
+                proxy_protocol: None,
+                routes: vec![],
+                hosts: HashMap::new(),
+                rewrites: vec![],
+                ip_family: IpFamilyPolicy::Any,
+                connection_attempt_delay: default_connection_attempt_delay(),
+                source: None,
+                tenant_sources: HashMap::new(),
+                tenant_allowed_targets: HashMap::new(),
+                server_name_allowed_targets: HashMap::new(),
+                socket_options: SocketOptions::default(),
+                connect_retries: 0,
+                retry_backoff: default_retry_backoff(),
+                circuit_breaker: None,
+                upstream_tls: None,
 
-            }
+            },
+            tcp_balancer: TcpBalancerConfig::default(),
+            tls: TlsListenerConfig::default(),
+            admin_socket: None,
 
         }
 
@@ -153,6 +831,11 @@ impl ProxyConfiguration {
         let config = cli.config;
         let bind_address = cli.bind;
 
+        let tunnel_config = match &config {
+            None => TunnelConfig::default(),
+            Some(config) => ProxyConfiguration::read_tunnel_config(config.as_str())?
+        };
+
         let mode = match cli.command {
             Command::Http(_) => {
                 info!(
@@ -162,34 +845,72 @@ impl ProxyConfiguration {
                 ProxyMode::Http
             },
             Command::Https(https) => {
-                let pkcs12_file = https.pk.as_str();
-                let password = https.password.as_str();
-
-                let identity = ProxyConfiguration::tls_identity_from_file(&pkcs12_file, &password)?;
+                let default = match (https.pk,https.cert,https.key) {
+                    (Some(file),_,_) => {
+                        let password = https.password.unwrap_or_default();
+                        info!(
+                            "Starting in HTTPS mode: pksc12: {}, password: {}, bind: {}, configuration: {:?}",
+                            file,
+                            !password.is_empty(),
+                            bind_address,
+                            config
+                        );
+                        IdentitySource::Pkcs12 { file, password }
+                    }
+                    (None,Some(cert),Some(key)) => {
+                        info!(
+                            "Starting in HTTPS mode: cert: {}, key: {}, bind: {}, configuration: {:?}",
+                            cert,
+                            key,
+                            bind_address,
+                            config
+                        );
+                        IdentitySource::Pem { cert, key }
+                    }
+                    _ => {
+                        error!("HTTPS mode needs --pk or both --cert and --key");
+                        return Err(Error::from(ErrorKind::InvalidInput));
+                    }
+                };
+                let source = TlsIdentitiesSource { default, tls: tunnel_config.tls.clone() };
+                ProxyMode::Https(source.load()?)
+            },
+            Command::Tcp(tcp) => {
+                let backends = tcp
+                    .destination
+                    .iter()
+                    .map(|destination| ProxyConfiguration::parse_tcp_backend(destination))
+                    .collect::<io::Result<Vec<TcpBackend>>>()?;
+                info!(
+                    "Starting in TCP mode: destionation: {:?}, configuration: {:?}",
+                    tcp.destination,config
+                );
+                ProxyMode::Tcp(backends)
+            },
+            Command::Socks5(socks5) => {
+                let users = match &socks5.users {
+                    Some(users_file) => ProxyConfiguration::read_socks5_users(users_file)?,
+                    None => HashMap::new(),
+                };
                 info!(
-                    "Starting in HTTPS mode: pksc12: {}, password: {}, bind: {}, configuration: {:?}",
-                    pkcs12_file,
-                    !password.is_empty(),
+                    "Starting in SOCKS5 mode: users: {}, bind: {}, configuration: {:?}",
+                    users.len(),
                     bind_address,
                     config
                 );
-                ProxyMode::Https(identity)
-            },
-            Command::Tcp(tcp) => {
-                let tcp = tcp.destination;
+                ProxyMode::Socks5(users)
+            }
+            Command::TlsPassthrough(passthrough) => {
                 info!(
-                    "Starting in TCP mode: destionation: {}, configuration: {:?}",
-                    tcp,config
+                    "Starting in TLS passthrough mode: port: {}, bind: {}, configuration: {:?}",
+                    passthrough.port,
+                    bind_address,
+                    config
                 );
-                ProxyMode::Tcp(tcp)
+                ProxyMode::TlsPassthrough(passthrough.port)
             }
         };
 
-        let tunnel_config = match config {
-            None => TunnelConfig::default(),
-            Some(config) => ProxyConfiguration::read_tunnel_config(config.as_str())?
-        };
-
         Ok(ProxyConfigurationBuilder::default()
             .bind_address(bind_address)
             .mode(mode)
@@ -199,7 +920,7 @@ impl ProxyConfiguration {
 
     }
 
-    fn tls_identity_from_file(file_path:&str,password:&str) -> io::Result<Identity> {
+    fn tls_identity_from_file(file_path:&str,password:&str) -> io::Result<TlsIdentity> {
         let mut file = File::open(file_path).map_err(|e| {
             error!("Error opening file PKSC12 {}: {}",file_path,e);
             e
@@ -209,12 +930,66 @@ impl ProxyConfiguration {
             error!("Error reading file: {}, {}",file_path,e);
             e
         })?;
-        Identity::from_pkcs12(&identity, &password).map_err(|e| {
+        pkcs12_identity(&identity, password).map_err(|e| {
             error!("Error authenting the Identity of file {}: {}",file_path,e);
             Error::from(ErrorKind::InvalidInput)
         })
     }
 
+    /// PEM chain and key, as written by cert-manager or ACME clients.
+    /// Whether the key matches the certificate is checked when the acceptor is built,
+    /// at startup and on every reload.
+    pub fn tls_identity_from_pem(cert_path:&str, key_path:&str) -> io::Result<TlsIdentity> {
+        let read = |file_path:&str| std::fs::read_to_string(file_path)
+            .inspect_err(|e| error!("Error reading file {}: {}", file_path, e));
+        let chain = read(cert_path)?;
+        if pem_block(&chain).is_none_or(|(label,_)| label != "CERTIFICATE") {
+            error!("No PEM certificate in {}", cert_path);
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        let key = pkcs8_pem(&read(key_path)?).inspect_err(|_| {
+            error!("Unsupported private key in {}, expected an unencrypted PKCS#8, PKCS#1 or SEC1 PEM", key_path);
+        })?;
+        Ok(TlsIdentity { chain, key })
+    }
+
+    fn parse_tcp_backend(destination:&str) -> io::Result<TcpBackend> {
+        let (address,weight) = match destination.rsplit_once('=') {
+            Some((address,weight)) => {
+                let weight = weight.parse::<u32>().map_err(|_| {
+                    error!("Bad weight in TCP destination {}", destination);
+                    Error::from(ErrorKind::InvalidInput)
+                })?;
+                (address,weight)
+            }
+            None => (destination,1),
+        };
+        Ok(TcpBackend {
+            address: address.to_string(),
+            weight,
+        })
+    }
+
+    fn read_socks5_users(file_path:&str) -> io::Result<HashMap<String,String>> {
+        let users = std::fs::read_to_string(file_path).map_err(|e| {
+            error!("Failed to read SOCKS5 users file {}: {}", file_path,e);
+            e
+        })?;
+
+        users
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split_once(':') {
+                Some((user,password)) => Ok((user.to_string(),password.to_string())),
+                None => {
+                    error!("Malformed line in SOCKS5 users file {}, expected `user:password`", file_path);
+                    Err(Error::from(ErrorKind::InvalidInput))
+                }
+            })
+            .collect()
+    }
+
     fn read_tunnel_config(file_path:&str) -> io::Result<TunnelConfig> {
         let mut file = File::open(file_path).map_err(|e| {
             error!("Failed to open tunnel config file {}: {}", file_path,e);
@@ -237,6 +1012,134 @@ impl ProxyConfiguration {
 }
 
 
+// rsaEncryption and id-ecPublicKey
+const RSA_ENCRYPTION_OID: &[u8] = &[0x06,0x09,0x2a,0x86,0x48,0x86,0xf7,0x0d,0x01,0x01,0x01];
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x06,0x07,0x2a,0x86,0x48,0xce,0x3d,0x02,0x01];
+
+/// The first private key of `pem`, as PKCS#8 PEM.
+/// PKCS#1 RSA and SEC1 EC keys are wrapped, Ed25519 keys only come as PKCS#8.
+fn pkcs8_pem(pem:&str) -> io::Result<String> {
+    let (label,der) = pem_block(pem).ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+    let (algorithm,key) = match label {
+        "PRIVATE KEY" => return Ok(pem.to_string()),
+        "RSA PRIVATE KEY" => (der_tlv(0x30, &[RSA_ENCRYPTION_OID, &[0x05,0x00]].concat()),der),
+        "EC PRIVATE KEY" => {
+            let curve = sec1_curve(&der).ok_or_else(|| Error::from(ErrorKind::InvalidData))?;
+            (der_tlv(0x30, &[EC_PUBLIC_KEY_OID, curve].concat()),der)
+        }
+        _ => return Err(Error::from(ErrorKind::InvalidData)),
+    };
+
+    // PrivateKeyInfo ::= SEQUENCE { version 0, algorithm, privateKey OCTET STRING }
+    let info = der_tlv(0x30, &[&[0x02,0x01,0x00][..], &algorithm, &der_tlv(0x04, &key)].concat());
+    Ok(pem_encode("PRIVATE KEY", &info))
+}
+
+fn pem_encode(label:&str, der:&[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Key and chain of a PKCS#12 bundle, leaf certificate first.
+#[cfg(not(feature = "rustls"))]
+fn pkcs12_identity(der:&[u8], password:&str) -> Result<TlsIdentity,String> {
+    let parsed = openssl::pkcs12::Pkcs12::from_der(der)
+        .and_then(|pkcs12| pkcs12.parse2(password))
+        .map_err(|e| e.to_string())?;
+    let (Some(key),Some(cert)) = (parsed.pkey,parsed.cert) else {
+        return Err("no certificate or no private key".to_string());
+    };
+
+    let mut chain = pem_encode("CERTIFICATE", &cert.to_der().map_err(|e| e.to_string())?);
+    for ca in parsed.ca.iter().flatten() {
+        chain.push_str(&pem_encode("CERTIFICATE", &ca.to_der().map_err(|e| e.to_string())?));
+    }
+    let key = key.private_key_to_pkcs8().map_err(|e| e.to_string())?;
+    Ok(TlsIdentity { chain, key: pem_encode("PRIVATE KEY", &key) })
+}
+
+/// Key and chain of a PKCS#12 bundle, leaf certificate first.
+#[cfg(feature = "rustls")]
+fn pkcs12_identity(der:&[u8], password:&str) -> Result<TlsIdentity,String> {
+    let keystore = p12_keystore::KeyStore::from_pkcs12(der, password).map_err(|e| e.to_string())?;
+    let Some((_,key_chain)) = keystore.private_key_chain() else {
+        return Err("no certificate or no private key".to_string());
+    };
+
+    let chain = key_chain.chain().iter().map(|cert| pem_encode("CERTIFICATE", cert.as_der())).collect();
+    Ok(TlsIdentity { chain, key: pem_encode("PRIVATE KEY", key_chain.key()) })
+}
+
+fn pem_block(pem:&str) -> Option<(&str,Vec<u8>)> {
+    let begin = pem.find("-----BEGIN ")?;
+    let rest = &pem[begin + "-----BEGIN ".len()..];
+    let (label,rest) = rest.split_once("-----")?;
+    let (body,_) = rest.split_once(&format!("-----END {}-----", label))?;
+    let body: String = body.split_whitespace().collect();
+    Some((label,STANDARD.decode(body).ok()?))
+}
+
+/// The named curve OID in the `[0]` parameters of a SEC1 `ECPrivateKey`.
+fn sec1_curve(der:&[u8]) -> Option<&[u8]> {
+    let (tag,mut fields) = der_read(der)?;
+    if tag != 0x30 {
+        return None;
+    }
+    while !fields.is_empty() {
+        let (tag,value) = der_read(fields)?;
+        let field_len = der_len_of(fields)?;
+        if tag == 0xa0 {
+            return Some(value);
+        }
+        fields = &fields[field_len..];
+    }
+    None
+}
+
+// tag and contents of the element at the start of `der`
+fn der_read(der:&[u8]) -> Option<(u8,&[u8])> {
+    let (header,len) = der_header(der)?;
+    Some((der[0],der.get(header..header + len)?))
+}
+
+fn der_len_of(der:&[u8]) -> Option<usize> {
+    der_header(der).map(|(header,len)| header + len)
+}
+
+// (header size, contents size)
+fn der_header(der:&[u8]) -> Option<(usize,usize)> {
+    let first = *der.get(1)?;
+    if first < 0x80 {
+        return Some((2,first as usize));
+    }
+    let octets = (first & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+        return None;
+    }
+    let len = der.get(2..2 + octets)?.iter().fold(0usize, |len,b| len << 8 | *b as usize);
+    Some((2 + octets,len))
+}
+
+fn der_tlv(tag:u8, value:&[u8]) -> Vec<u8> {
+    let mut tlv = vec![tag];
+    match value.len() {
+        len if len < 0x80 => tlv.push(len as u8),
+        len => {
+            let octets: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+            tlv.push(0x80 | octets.len() as u8);
+            tlv.extend(octets);
+        }
+    }
+    tlv.extend_from_slice(value);
+    tlv
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -265,6 +1168,131 @@ mod test {
         Ok(file)
     }
 
+    #[test]
+    fn test_rewrite_target_and_static_addrs() {
+        let mut config = TunnelConfig::default().target_connection;
+        config.rewrites = vec![
+            RewriteRule {
+                target: Regex::new(r"^api\.partner\.com:443$").unwrap(),
+                rewrite_to: "partner-gw.internal:8443".to_string(),
+            },
+            RewriteRule {
+                target: Regex::new(r"^(?P<host>[^:]+)\.legacy:(\d+)$").unwrap(),
+                rewrite_to: "${host}.modern:$2".to_string(),
+            },
+        ];
+        config.hosts.insert("Partner-GW.internal".to_string(), vec!["10.1.2.3".parse().unwrap(), "fd00::3".parse().unwrap()]);
+
+        assert_eq!(config.rewrite_target("api.partner.com:443").as_deref(), Some("partner-gw.internal:8443"));
+        assert_eq!(config.rewrite_target("db.legacy:5432").as_deref(), Some("db.modern:5432"));
+        assert_eq!(config.rewrite_target("api.partner.com:80"), None);
+
+        assert_eq!(
+            config.static_addrs("partner-gw.internal.:8443"),
+            Some(vec!["10.1.2.3:8443".parse().unwrap(), "[fd00::3]:8443".parse().unwrap()])
+        );
+        assert_eq!(config.static_addrs("partner-gw.example:8443"), None);
+    }
+
+    #[test]
+    fn test_ip_family_policy() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let apply = |policy:IpFamilyPolicy| -> Vec<String> {
+            policy.apply(addrs.clone()).iter().map(|a| a.to_string()).collect()
+        };
+
+        assert_eq!(apply(IpFamilyPolicy::Ipv4Only), ["10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(apply(IpFamilyPolicy::Ipv6Only), ["[::1]:80", "[::2]:80"]);
+        assert_eq!(apply(IpFamilyPolicy::PreferIpv4), ["10.0.0.1:80", "10.0.0.2:80", "[::1]:80", "[::2]:80"]);
+        assert_eq!(apply(IpFamilyPolicy::PreferIpv6), ["[::1]:80", "[::2]:80", "10.0.0.1:80", "10.0.0.2:80"]);
+
+        let mut config = TunnelConfig::default().target_connection;
+        config.ip_family = IpFamilyPolicy::Ipv4Only;
+        config.routes = vec![RouteConfig {
+            targets: Regex::new(r"\.v6\.internal:\d+$").unwrap(),
+            proxy_protocol: None,
+            ip_family: Some(IpFamilyPolicy::Ipv6Only),
+            parent_proxies: vec![],
+            source: None,
+            socket_options: None,
+            upstream_tls: None,
+        }];
+        assert_eq!(config.ip_family_for("db.v6.internal:5432"), IpFamilyPolicy::Ipv6Only);
+        assert_eq!(config.ip_family_for("example.com:443"), IpFamilyPolicy::Ipv4Only);
+    }
+
+    #[test]
+    fn test_tenant_allowed_targets() {
+        let config: TargetConnectionConfig = serde_yaml::from_str(r#"
+            dns_cache_ttl: 60s
+            allowed_targets: "^[^:]+:443$"
+            connection_timeout: 10s
+            relay_policy:
+              idle_timeout: 10m
+              min_rate_bpm: 0
+              max_rate_bps: 0
+            tenant_allowed_targets:
+              device-1: "^telemetry\\.example\\.com:443$"
+            server_name_allowed_targets:
+              team-a.proxy.example: "^[^:]+\\.team-a\\.example:443$"
+        "#).unwrap();
+
+        assert!(config.allowed_targets_for(Some("device-1"), None).is_match("telemetry.example.com:443"));
+        assert!(!config.allowed_targets_for(Some("device-1"), None).is_match("example.com:443"));
+        assert!(config.allowed_targets_for(Some("device-2"), None).is_match("example.com:443"));
+        assert!(config.allowed_targets_for(None, None).is_match("example.com:443"));
+
+        let team_a = Some("team-a.proxy.example");
+        assert!(config.allowed_targets_for(None, team_a).is_match("db.team-a.example:443"));
+        assert!(!config.allowed_targets_for(None, team_a).is_match("example.com:443"));
+        assert!(config.allowed_targets_for(None, Some("team-b.proxy.example")).is_match("example.com:443"));
+        // a tenant ACL is more specific than the name the client connected to
+        assert!(config.allowed_targets_for(Some("device-1"), team_a).is_match("telemetry.example.com:443"));
+    }
+
+    #[test]
+    fn test_source_selection() {
+        let source = |addresses:&[&str]| SourceConfig {
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        let mut config = TunnelConfig::default().target_connection;
+        config.source = Some(source(&["192.0.2.1", "192.0.2.2", "2001:db8::1"]));
+        config.tenant_sources.insert("billy".to_string(), source(&["192.0.2.10"]));
+        config.routes = vec![RouteConfig {
+            targets: Regex::new(r"\.partner\.com:\d+$").unwrap(),
+            proxy_protocol: None,
+            ip_family: None,
+            parent_proxies: vec![],
+            source: Some(source(&["192.0.2.20"])),
+            socket_options: None,
+            upstream_tls: None,
+        }];
+
+        let v4: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8:1::1]:443".parse().unwrap();
+        let pick = |target:&str, user:Option<&str>, addr:&SocketAddr| {
+            config.source_for(target, user).and_then(|source| source.next_address(addr)).map(|ip| ip.to_string())
+        };
+
+        assert_eq!(pick("example.com:443", None, &v4).as_deref(), Some("192.0.2.1"));
+        // clones share the rotation
+        let cloned = config.clone();
+        assert_eq!(cloned.source_for("example.com:443", None).unwrap().next_address(&v4).map(|ip| ip.to_string()).as_deref(), Some("192.0.2.2"));
+        assert_eq!(pick("example.com:443", None, &v4).as_deref(), Some("192.0.2.1"));
+        assert_eq!(pick("example.com:443", None, &v6).as_deref(), Some("2001:db8::1"));
+        assert_eq!(pick("api.partner.com:443", None, &v4).as_deref(), Some("192.0.2.20"));
+        assert_eq!(pick("api.partner.com:443", Some("billy"), &v4).as_deref(), Some("192.0.2.10"));
+
+        let tenant = config.source_for("example.com:443", Some("billy")).unwrap();
+        assert!(tenant.allows(&v4));
+        assert!(!tenant.allows(&v6));
+        assert_eq!(tenant.next_address(&v6), None);
+    }
+
     #[test]
     fn test_http_mode() -> io::Result<()> {
         let args = vec![
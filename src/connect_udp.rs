@@ -0,0 +1,303 @@
+use std::{pin::Pin, task::{Context, Poll, ready}};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::debug;
+use tokio::{io::{self, AsyncRead, AsyncWrite, ReadBuf}, net::UdpSocket};
+
+/// RFC 9298 default template, `{target_host}` and `{target_port}` are the only variables.
+pub const CONNECT_UDP_URI_TEMPLATE: &str = "/.well-known/masque/udp/{target_host}/{target_port}/";
+pub const CONNECT_UDP_PROTOCOL: &str = "connect-udp";
+
+const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+// context ID 0 carries UDP payloads, other contexts are extensions we don't know
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0x00;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+// a datagram and the longest context ID varint, nothing we accept is larger
+const MAX_CAPSULE_SIZE: usize = MAX_DATAGRAM_SIZE + 8;
+
+/// Extracts the `host:port` target from a request path matching `template`.
+/// Absolute-form URIs are accepted, the scheme and authority are ignored.
+pub fn match_uri_template(template:&str, uri:&str) -> Option<String> {
+    let path = match uri.find("://") {
+        Some(scheme_end) => {
+            let authority_start = scheme_end + 3;
+            &uri[authority_start + uri[authority_start..].find('/')?..]
+        }
+        None => uri,
+    };
+
+    let mut host = None;
+    let mut port = None;
+    let mut path_segments = path.split('/');
+
+    for template_segment in template.split('/') {
+        let path_segment = path_segments.next()?;
+        match template_segment {
+            "{target_host}" => host = Some(percent_decode(path_segment)?),
+            "{target_port}" => port = Some(path_segment.parse::<u16>().ok()?),
+            literal if literal == path_segment => {}
+            _ => return None,
+        }
+    }
+    if path_segments.next().is_some() {
+        return None;
+    }
+
+    let host = host?;
+    let port = port?;
+    if host.is_empty() {
+        None
+    } else if host.contains(':') {
+        Some(format!("[{}]:{}", host, port))
+    } else {
+        Some(format!("{}:{}", host, port))
+    }
+}
+
+fn percent_decode(segment:&str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// QUIC variable-length integer (RFC 9000, section 16).
+/// Returns the value and its encoded length, or `None` if incomplete.
+fn decode_varint(src:&[u8]) -> Option<(u64,usize)> {
+    let first = *src.first()?;
+    let len = 1 << (first >> 6);
+    if src.len() < len {
+        return None;
+    }
+    let mut value = (first & 0x3f) as u64;
+    for byte in &src[1..len] {
+        value = (value << 8) | *byte as u64;
+    }
+    Some((value,len))
+}
+
+fn encode_varint(value:u64, dst:&mut BytesMut) {
+    if value < 1 << 6 {
+        dst.put_u8(value as u8);
+    } else if value < 1 << 14 {
+        dst.put_u16(value as u16 | 0x4000);
+    } else if value < 1 << 30 {
+        dst.put_u32(value as u32 | 0x8000_0000);
+    } else {
+        dst.put_u64(value | 0xc000_0000_0000_0000);
+    }
+}
+
+/// Bridges the capsule stream of an upgraded connect-udp request and a UDP socket
+/// connected to the target. Writes carry capsules from the client, each DATAGRAM
+/// capsule becomes a UDP datagram; every datagram from the target is read back as
+/// a DATAGRAM capsule. This lets the regular relay move the data and account it.
+pub struct UdpCapsuleStream {
+    socket: UdpSocket,
+    // capsules from the client that are not complete or not sent yet
+    incoming: BytesMut,
+    // encoded capsule not yet returned to the reader
+    outgoing: BytesMut,
+    recv_buffer: Vec<u8>,
+}
+
+impl UdpCapsuleStream {
+    pub fn new(socket:UdpSocket) -> Self {
+        Self {
+            socket,
+            incoming: BytesMut::new(),
+            outgoing: BytesMut::new(),
+            recv_buffer: vec![0u8;MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    /// Sends every complete capsule in `incoming`, unknown capsules are skipped.
+    fn poll_send_capsules(&mut self, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let (capsule_type,type_len) = match decode_varint(&self.incoming) {
+                Some(v) => v,
+                None => return Poll::Ready(Ok(())),
+            };
+            let (capsule_len,len_len) = match decode_varint(&self.incoming[type_len..]) {
+                Some(v) => v,
+                None => return Poll::Ready(Ok(())),
+            };
+            let header_len = type_len + len_len;
+            // buffering stops here, a client can't make us hold an arbitrarily long capsule
+            if capsule_len > MAX_CAPSULE_SIZE as u64 {
+                debug!("Capsule of {} bytes is larger than {}", capsule_len, MAX_CAPSULE_SIZE);
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::InvalidData)));
+            }
+            let capsule_len = capsule_len as usize;
+            if self.incoming.len() < header_len + capsule_len {
+                return Poll::Ready(Ok(()));
+            }
+
+            let value = &self.incoming[header_len..header_len + capsule_len];
+            if capsule_type == CAPSULE_TYPE_DATAGRAM {
+                match decode_varint(value) {
+                    Some((UDP_PAYLOAD_CONTEXT_ID,context_len)) => match ready!(self.socket.poll_send(cx, &value[context_len..])) {
+                        Ok(_) => {}
+                        // ICMP unreachable for an earlier datagram, UDP may lose this one too
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => debug!("Dropping datagram: {}", e),
+                        Err(e) => return Poll::Ready(Err(e)),
+                    },
+                    _ => debug!("Dropping HTTP datagram with unknown context"),
+                }
+            } else {
+                debug!("Skipping unknown capsule type {}", capsule_type);
+            }
+            self.incoming.advance(header_len + capsule_len);
+        }
+    }
+}
+
+impl AsyncRead for UdpCapsuleStream {
+    fn poll_read(self:Pin<&mut Self>, cx:&mut Context<'_>, buf:&mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.outgoing.is_empty() {
+            let mut datagram = ReadBuf::new(&mut this.recv_buffer);
+            loop {
+                match ready!(this.socket.poll_recv(cx, &mut datagram)) {
+                    Ok(()) => break,
+                    // ICMP unreachable for an earlier datagram, the target may still answer
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => debug!("Target unreachable: {}", e),
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+            let payload = datagram.filled();
+
+            encode_varint(CAPSULE_TYPE_DATAGRAM, &mut this.outgoing);
+            encode_varint(payload.len() as u64 + 1, &mut this.outgoing);
+            encode_varint(UDP_PAYLOAD_CONTEXT_ID, &mut this.outgoing);
+            this.outgoing.put_slice(payload);
+        }
+
+        let len = this.outgoing.len().min(buf.remaining());
+        buf.put_slice(&this.outgoing.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpCapsuleStream {
+    fn poll_write(self:Pin<&mut Self>, cx:&mut Context<'_>, buf:&[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // don't buffer more while the previous capsules are still waiting for the socket
+        ready!(this.poll_send_capsules(cx))?;
+        this.incoming.put_slice(buf);
+        // whatever the socket can't take now is sent on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_send_capsules(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self:Pin<&mut Self>, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_capsules(cx)
+    }
+
+    fn poll_shutdown(self:Pin<&mut Self>, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_match_uri_template() {
+        let template = CONNECT_UDP_URI_TEMPLATE;
+        assert_eq!(
+            match_uri_template(template, "/.well-known/masque/udp/192.0.2.6/443/"),
+            Some("192.0.2.6:443".to_string())
+        );
+        assert_eq!(
+            match_uri_template(template, "https://proxy.example.org/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/"),
+            Some("[2001:db8::42]:53".to_string())
+        );
+        assert_eq!(match_uri_template(template, "/.well-known/masque/udp/example.com/http/"), None);
+        assert_eq!(match_uri_template(template, "/.well-known/masque/tcp/example.com/443/"), None);
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0u64, 63, 64, 16383, 16384, 1 << 30] {
+            let mut encoded = BytesMut::new();
+            encode_varint(value, &mut encoded);
+            assert_eq!(decode_varint(&encoded), Some((value,encoded.len())));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capsule_round_trip() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(target.local_addr().unwrap()).await.unwrap();
+        let mut stream = UdpCapsuleStream::new(socket);
+
+        // a capsule split across two writes
+        stream.write_all(&[0x00, 0x05, 0x00, b'p']).await.unwrap();
+        stream.write_all(b"ing").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buffer = [0u8;16];
+        let (len,from) = target.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"ping");
+
+        target.send_to(b"pong", from).await.unwrap();
+        let mut capsule = [0u8;7];
+        stream.read_exact(&mut capsule).await.unwrap();
+        assert_eq!(&capsule, &[0x00, 0x05, 0x00, b'p', b'o', b'n', b'g']);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_target() {
+        // nothing listens there, the first datagram draws an ICMP port unreachable
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(closed.local_addr().unwrap()).await.unwrap();
+        let target = closed.local_addr().unwrap();
+        drop(closed);
+        let mut stream = UdpCapsuleStream::new(socket);
+
+        stream.write_all(&[0x00, 0x05, 0x00, b'p', b'i', b'n', b'g']).await.unwrap();
+        stream.flush().await.unwrap();
+        stream.write_all(&[0x00, 0x05, 0x00, b'p', b'i', b'n', b'g']).await.unwrap();
+        stream.flush().await.unwrap();
+
+        // the target comes up and answers, the tunnel is still there for it
+        let target = UdpSocket::bind(target).await.unwrap();
+        let from = stream.socket.local_addr().unwrap();
+        target.send_to(b"pong", from).await.unwrap();
+        let mut capsule = [0u8;7];
+        stream.read_exact(&mut capsule).await.unwrap();
+        assert_eq!(&capsule, &[0x00, 0x05, 0x00, b'p', b'o', b'n', b'g']);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_capsule() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect("127.0.0.1:9").await.unwrap();
+        let mut stream = UdpCapsuleStream::new(socket);
+
+        // DATAGRAM capsule announcing 2^62 - 1 bytes
+        let mut header = BytesMut::new();
+        encode_varint(CAPSULE_TYPE_DATAGRAM, &mut header);
+        encode_varint((1 << 62) - 1, &mut header);
+        let written = stream.write_all(&header).await;
+        assert_eq!(written.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,688 @@
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, time::Duration};
+
+use bytes::{BufMut, BytesMut};
+use log::{debug, error};
+use rand::Rng;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    configuration::DnsBackendConfig,
+    tls_client::{self, ClientStream, Connector},
+};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+const MAX_MESSAGE_SIZE: usize = 65535;
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+
+/// Answers `host:port` lookups with the configured backend.
+/// Everything but `System` speaks the DNS wire protocol to the configured servers,
+/// asking for AAAA and A records in parallel.
+#[derive(Clone)]
+pub struct DnsBackend {
+    config: DnsBackendConfig,
+    query_timeout: Duration,
+    tls_connector: Option<Connector>,
+}
+
+enum Transport<'a> {
+    Udp,
+    Tcp,
+    Tls(&'a str),
+}
+
+impl DnsBackend {
+    pub fn new(config:DnsBackendConfig, query_timeout:Duration) -> io::Result<Self> {
+        let tls_connector = match &config {
+            DnsBackendConfig::Tls { .. } | DnsBackendConfig::Https { .. } => {
+                Some(tls_client::system_connector().inspect_err(|_| error!("Failed to create TLS connector for DNS"))?)
+            }
+            _ => None,
+        };
+
+        if let DnsBackendConfig::Https { url, addresses } = &config {
+            let url = DohUrl::parse(url)?;
+            if addresses.is_empty() && url.host.parse::<IpAddr>().is_err() {
+                error!("DNS-over-HTTPS host {} needs `addresses`, it can't be resolved", url.host);
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+        }
+
+        Ok(Self {
+            config,
+            query_timeout,
+            tls_connector,
+        })
+    }
+
+    pub async fn lookup(&self, target:&str) -> io::Result<Vec<SocketAddr>> {
+        if let DnsBackendConfig::System = self.config {
+            return Ok(tokio::net::lookup_host(target).await?.collect());
+        }
+
+        let (host,port) = split_host_port(target)?;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip,port)]);
+        }
+
+        let (ipv6,ipv4) = tokio::join!(self.query(host, TYPE_AAAA), self.query(host, TYPE_A));
+        let addrs: Vec<SocketAddr> = match (ipv6,ipv4) {
+            (Err(e),Err(_)) => return Err(e),
+            (ipv6,ipv4) => ipv6
+                .unwrap_or_default()
+                .into_iter()
+                .chain(ipv4.unwrap_or_default())
+                .map(|ip| SocketAddr::new(ip,port))
+                .collect(),
+        };
+        Ok(addrs)
+    }
+
+    async fn query(&self, host:&str, record_type:u16) -> io::Result<Vec<IpAddr>> {
+        let id: u16 = rand::rng().random();
+        let query = encode_query(id, host, record_type)?;
+
+        let response = match &self.config {
+            DnsBackendConfig::System => unreachable!("system lookups don't build queries"),
+            DnsBackendConfig::Udp { nameservers } => self.exchange(nameservers, Transport::Udp, &query).await?,
+            DnsBackendConfig::Tcp { nameservers } => self.exchange(nameservers, Transport::Tcp, &query).await?,
+            DnsBackendConfig::Tls { nameservers, server_name } => {
+                self.exchange(nameservers, Transport::Tls(server_name), &query).await?
+            }
+            DnsBackendConfig::Https { url, addresses } => self.exchange_https(url, addresses, &query).await?,
+        };
+
+        match decode_response(id, record_type, &response)? {
+            Response::Answers(ips) => Ok(ips),
+            Response::Truncated => {
+                error!("Truncated DNS response for {}", host);
+                Err(io::Error::from(io::ErrorKind::InvalidData))
+            }
+        }
+    }
+
+    /// Tries the nameservers in order, the first one that answers wins.
+    async fn exchange(&self, nameservers:&[SocketAddr], transport:Transport<'_>, query:&[u8]) -> io::Result<Vec<u8>> {
+        let mut last_error = io::Error::from(io::ErrorKind::AddrNotAvailable);
+
+        for nameserver in nameservers {
+            let exchange = async {
+                match transport {
+                    Transport::Udp => {
+                        let response = exchange_udp(*nameserver, query).await?;
+                        if is_truncated(&response) {
+                            debug!("Truncated response from {}, retrying over TCP", nameserver);
+                            exchange_stream(TcpStream::connect(nameserver).await?, query).await
+                        } else {
+                            Ok(response)
+                        }
+                    }
+                    Transport::Tcp => exchange_stream(TcpStream::connect(nameserver).await?, query).await,
+                    Transport::Tls(server_name) => {
+                        let stream = TcpStream::connect(nameserver).await?;
+                        let stream = self.tls_connect(server_name, stream).await?;
+                        exchange_stream(stream, query).await
+                    }
+                }
+            };
+
+            match timeout(self.query_timeout, exchange).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => {
+                    debug!("DNS query to {} failed: {}", nameserver, e);
+                    last_error = e;
+                }
+                Err(_) => {
+                    debug!("DNS query to {} timed out", nameserver);
+                    last_error = io::Error::from(io::ErrorKind::TimedOut);
+                }
+            }
+        }
+
+        error!("No nameserver answered the DNS query: {}", last_error);
+        Err(last_error)
+    }
+
+    async fn exchange_https(&self, url:&str, addresses:&[SocketAddr], query:&[u8]) -> io::Result<Vec<u8>> {
+        let url = DohUrl::parse(url)?;
+        let addresses = match url.host.parse::<IpAddr>() {
+            Ok(ip) if addresses.is_empty() => vec![SocketAddr::new(ip,url.port)],
+            _ => addresses.to_vec(),
+        };
+
+        let mut last_error = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        for address in addresses {
+            let exchange = async {
+                let stream = TcpStream::connect(address).await?;
+                if url.tls {
+                    let stream = self.tls_connect(&url.host, stream).await?;
+                    post_dns_message(stream, &url, query).await
+                } else {
+                    post_dns_message(stream, &url, query).await
+                }
+            };
+
+            match timeout(self.query_timeout, exchange).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(e)) => {
+                    debug!("DNS-over-HTTPS query to {} failed: {}", address, e);
+                    last_error = e;
+                }
+                Err(_) => {
+                    debug!("DNS-over-HTTPS query to {} timed out", address);
+                    last_error = io::Error::from(io::ErrorKind::TimedOut);
+                }
+            }
+        }
+
+        error!("No DNS-over-HTTPS server answered the DNS query: {}", last_error);
+        Err(last_error)
+    }
+
+    async fn tls_connect(&self, server_name:&str, stream:TcpStream) -> io::Result<ClientStream<TcpStream>> {
+        let connector = self.tls_connector.as_ref().expect("Bug: TLS connector for a plain DNS backend");
+        tls_client::connect(connector, server_name, stream).await.map_err(|e| {
+            debug!("TLS handshake with DNS server {} failed: {}", server_name, e);
+            io::Error::from(io::ErrorKind::ConnectionAborted)
+        })
+    }
+}
+
+/// Splits `host:port`, IPv6 hosts are bracketed.
+fn split_host_port(target:&str) -> io::Result<(&str,u16)> {
+    let (host,port) = target.rsplit_once(':').ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let port = port.parse::<u16>().map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    Ok((host,port))
+}
+
+async fn exchange_udp(nameserver:SocketAddr, query:&[u8]) -> io::Result<Vec<u8>> {
+    let bind_addr: SocketAddr = if nameserver.is_ipv4() { ([0,0,0,0],0).into() } else { ([0u16;8],0).into() };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0u8;MAX_MESSAGE_SIZE];
+    loop {
+        let len = socket.recv(&mut buffer).await?;
+        // anything not answering our query id is stale or spoofed
+        if len >= 2 && buffer[..2] == query[..2] {
+            buffer.truncate(len);
+            return Ok(buffer);
+        }
+        debug!("Ignoring DNS response with unexpected id from {}", nameserver);
+    }
+}
+
+/// TCP framing (RFC 1035, section 4.2.2), also used by DNS-over-TLS.
+async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(mut stream:S, query:&[u8]) -> io::Result<Vec<u8>> {
+    let mut message = BytesMut::with_capacity(query.len() + 2);
+    message.put_u16(query.len() as u16);
+    message.put_slice(query);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut response = vec![0u8;len];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+struct DohUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl DohUrl {
+    // plain `http://` is accepted for a local sidecar
+    fn parse(url:&str) -> io::Result<Self> {
+        let invalid = || {
+            error!("Bad DNS-over-HTTPS URL {}", url);
+            io::Error::from(io::ErrorKind::InvalidInput)
+        };
+
+        let (tls,rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true,rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false,rest)
+        } else {
+            return Err(invalid());
+        };
+
+        let (authority,path) = match rest.find('/') {
+            Some(i) => (&rest[..i],&rest[i..]),
+            None => (rest,"/"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host,port) = match split_host_port(authority) {
+            Ok((host,port)) => (host,port),
+            Err(_) if !authority.is_empty() => {
+                let host = authority.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(authority);
+                (host,default_port)
+            }
+            Err(_) => return Err(invalid()),
+        };
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// `host:port` for the `Host` header, IPv6 literals bracketed.
+    fn host_header(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// RFC 8484 POST over HTTP/1.1, one query per connection.
+async fn post_dns_message<S: AsyncRead + AsyncWrite + Unpin>(mut stream:S, url:&DohUrl, query:&[u8]) -> io::Result<Vec<u8>> {
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path, url.host_header(), DNS_MESSAGE_TYPE, DNS_MESSAGE_TYPE, query.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    (&mut stream).take(MAX_MESSAGE_SIZE as u64 + 4096).read_to_end(&mut response).await?;
+    decode_http_response(&response)
+}
+
+fn decode_http_response(response:&[u8]) -> io::Result<Vec<u8>> {
+    let bad_response = |reason:&str| {
+        error!("Bad DNS-over-HTTPS response: {}", reason);
+        io::Error::from(io::ErrorKind::InvalidData)
+    };
+
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| bad_response("no header"))?;
+    let header = std::str::from_utf8(&response[..header_end]).map_err(|_| bad_response("header is not UTF-8"))?;
+    let body = &response[header_end + 4..];
+
+    let mut lines = header.split("\r\n");
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    if status != Some("200") {
+        return Err(bad_response(&format!("status {:?}", status)));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name,value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().ok();
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    if chunked {
+        return decode_chunked(body).ok_or_else(|| bad_response("broken chunked body"));
+    }
+    match content_length {
+        Some(len) if len <= body.len() => Ok(body[..len].to_vec()),
+        Some(_) => Err(bad_response("short body")),
+        None => Ok(body.to_vec()),
+    }
+}
+
+fn decode_chunked(mut body:&[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+fn encode_query(id:u16, host:&str, record_type:u16) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        error!("Bad DNS name {}", host);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.is_empty() || name.len() > 253 {
+        return Err(invalid());
+    }
+
+    let mut query = BytesMut::with_capacity(name.len() + 18);
+    query.put_u16(id);
+    query.put_u16(FLAG_RECURSION_DESIRED);
+    // one question, no answer, authority or additional records
+    query.put_slice(&[0,1, 0,0, 0,0, 0,0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+        query.put_u8(label.len() as u8);
+        query.put_slice(label.as_bytes());
+    }
+    query.put_u8(0);
+    query.put_u16(record_type);
+    query.put_u16(CLASS_IN);
+    Ok(query.to_vec())
+}
+
+#[derive(Debug,PartialEq)]
+enum Response {
+    Answers(Vec<IpAddr>),
+    Truncated,
+}
+
+fn is_truncated(response:&[u8]) -> bool {
+    response.len() >= 4 && u16::from_be_bytes([response[2],response[3]]) & FLAG_TRUNCATED != 0
+}
+
+/// Extracts the records of `record_type`, CNAMEs are followed by the recursive
+/// resolver and their targets show up in the same answer section.
+/// NXDOMAIN is an empty answer, other errors fail.
+fn decode_response(id:u16, record_type:u16, response:&[u8]) -> io::Result<Response> {
+    let bad_response = |reason:&str| {
+        error!("Bad DNS response: {}", reason);
+        io::Error::from(io::ErrorKind::InvalidData)
+    };
+
+    let read_u16 = |at:usize| response.get(at..at + 2).map(|b| u16::from_be_bytes([b[0],b[1]]));
+    let (Some(response_id),Some(flags),Some(questions),Some(answers)) = (read_u16(0),read_u16(2),read_u16(4),read_u16(6)) else {
+        return Err(bad_response("short header"));
+    };
+
+    if response_id != id || flags & FLAG_RESPONSE == 0 {
+        return Err(bad_response("not an answer to the query"));
+    }
+    if flags & FLAG_TRUNCATED != 0 {
+        return Ok(Response::Truncated);
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Response::Answers(vec![])),
+        rcode => {
+            error!("DNS server failed the query, RCODE={}", rcode);
+            return Err(io::Error::from(io::ErrorKind::Other));
+        }
+    }
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(response, at).ok_or_else(|| bad_response("bad question"))? + 4;
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(response, at).ok_or_else(|| bad_response("bad answer name"))?;
+        let (Some(answer_type),Some(class),Some(len)) = (read_u16(at),read_u16(at + 2),read_u16(at + 8)) else {
+            return Err(bad_response("short answer"));
+        };
+        let data = response.get(at + 10..at + 10 + len as usize).ok_or_else(|| bad_response("short record"))?;
+        at += 10 + len as usize;
+
+        if answer_type != record_type || class != CLASS_IN {
+            continue;
+        }
+        match data.len() {
+            4 => ips.push(IpAddr::V4(Ipv4Addr::new(data[0],data[1],data[2],data[3]))),
+            16 => ips.push(IpAddr::V6(Ipv6Addr::from(<[u8;16]>::try_from(data).expect("Bug: length checked")))),
+            _ => return Err(bad_response("bad address length")),
+        }
+    }
+    Ok(Response::Answers(ips))
+}
+
+/// Returns the offset right after the name at `at`, a compression pointer ends the name.
+fn skip_name(message:&[u8], mut at:usize) -> Option<usize> {
+    loop {
+        let len = *message.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => {
+                message.get(at + 1)?;
+                return Some(at + 2);
+            }
+            len => at += len + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_certs::self_signed;
+    use native_tls::Identity;
+    use std::future::Future;
+    use tokio::net::TcpListener;
+    use tokio_native_tls::TlsAcceptor;
+
+    /// Answers like a recursive resolver: `*.test` names have 192.0.2.1 and 2001:db8::1,
+    /// `missing.test` doesn't exist.
+    fn stub_answer(query:&[u8]) -> Vec<u8> {
+        let question_end = skip_name(query, 12).unwrap() + 4;
+        let record_type = u16::from_be_bytes([query[question_end - 4],query[question_end - 3]]);
+        let missing = query[12..].starts_with(b"\x07missing");
+
+        let mut answer = BytesMut::new();
+        answer.put_slice(&query[..2]);
+        answer.put_u16(FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080 | if missing { RCODE_NXDOMAIN } else { 0 });
+        answer.put_u16(1);
+        answer.put_u16(if missing { 0 } else { 1 });
+        answer.put_slice(&[0,0, 0,0]);
+        answer.put_slice(&query[12..question_end]);
+        if !missing {
+            // pointer to the question name
+            answer.put_u16(0xc00c);
+            answer.put_u16(record_type);
+            answer.put_u16(CLASS_IN);
+            answer.put_u32(60);
+            if record_type == TYPE_A {
+                answer.put_u16(4);
+                answer.put_slice(&[192,0,2,1]);
+            } else {
+                answer.put_u16(16);
+                answer.put_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+            }
+        }
+        answer.to_vec()
+    }
+
+    async fn udp_stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8;512];
+            loop {
+                let (len,from) = socket.recv_from(&mut buffer).await.unwrap();
+                socket.send_to(&stub_answer(&buffer[..len]), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// A certificate for `dns.test`: the acceptor for the stubs, and the connector trusting it.
+    fn test_tls() -> (TlsAcceptor,Connector) {
+        let (cert,key) = self_signed("dns.test");
+        let identity = Identity::from_pkcs8(&cert, &key).unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let connector = tls_client::connector(Some(std::str::from_utf8(&cert).unwrap()), None).unwrap();
+        (acceptor,connector)
+    }
+
+    /// Serves each connection with `serve`, behind TLS if there's an acceptor.
+    async fn stream_stub<F,Fut>(tls:Option<TlsAcceptor>, serve:F) -> SocketAddr
+    where
+        F: Fn(Box<dyn Stream>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream,_) = listener.accept().await.unwrap();
+                match &tls {
+                    // clients not trusting the certificate give up on the handshake
+                    Some(acceptor) => if let Ok(stream) = acceptor.accept(stream).await {
+                        serve(Box::new(stream)).await
+                    },
+                    None => serve(Box::new(stream)).await,
+                }
+            }
+        });
+        addr
+    }
+
+    trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+    impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+    async fn tcp_stub(tls:Option<TlsAcceptor>) -> SocketAddr {
+        stream_stub(tls, |mut stream| async move {
+            let len = stream.read_u16().await.unwrap() as usize;
+            let mut query = vec![0u8;len];
+            stream.read_exact(&mut query).await.unwrap();
+            let answer = stub_answer(&query);
+            stream.write_u16(answer.len() as u16).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }).await
+    }
+
+    async fn http_stub(tls:Option<TlsAcceptor>) -> SocketAddr {
+        stream_stub(tls, |mut stream| async move {
+            let mut request = vec![0u8;1024];
+            let mut len = 0;
+            // the whole request fits into one buffer, wait for the body behind the header
+            let query = loop {
+                len += stream.read(&mut request[len..]).await.unwrap();
+                let Some(end) = request[..len].windows(4).position(|w| w == b"\r\n\r\n") else { continue };
+                let header = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                let content_length: usize = header
+                    .split("content-length: ")
+                    .nth(1)
+                    .and_then(|rest| rest.split("\r\n").next())
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if len >= end + 4 + content_length {
+                    break request[end + 4..len].to_vec();
+                }
+            };
+            let answer = stub_answer(&query);
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", DNS_MESSAGE_TYPE, answer.len());
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }).await
+    }
+
+    fn expected(port:u16) -> Vec<SocketAddr> {
+        vec![
+            SocketAddr::new("2001:db8::1".parse().unwrap(),port),
+            SocketAddr::new("192.0.2.1".parse().unwrap(),port),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_udp_and_tcp_nameservers() {
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let udp = DnsBackend::new(DnsBackendConfig::Udp { nameservers: vec![dead,udp_stub().await] }, Duration::from_millis(200)).unwrap();
+        assert_eq!(udp.lookup("www.example.test:443").await.unwrap(), expected(443));
+
+        let tcp = DnsBackend::new(DnsBackendConfig::Tcp { nameservers: vec![tcp_stub(None).await] }, Duration::from_secs(1)).unwrap();
+        assert_eq!(tcp.lookup("www.example.test:80").await.unwrap(), expected(80));
+        assert!(tcp.lookup("missing.test:80").await.unwrap().is_empty());
+        // literals never reach the nameservers
+        assert_eq!(tcp.lookup("[::1]:53").await.unwrap(), vec!["[::1]:53".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_http() {
+        let addr = http_stub(None).await;
+        let backend = DnsBackend::new(
+            DnsBackendConfig::Https { url: format!("http://{}/dns-query", addr), addresses: vec![] },
+            Duration::from_secs(1),
+        ).unwrap();
+        assert_eq!(backend.lookup("www.example.test:443").await.unwrap(), expected(443));
+
+        let unresolvable = DnsBackendConfig::Https { url: "https://dns.example.org/dns-query".to_string(), addresses: vec![] };
+        assert!(DnsBackend::new(unresolvable, Duration::from_secs(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_tls() {
+        let (acceptor,connector) = test_tls();
+        let config = DnsBackendConfig::Tls { nameservers: vec![tcp_stub(Some(acceptor)).await], server_name: "dns.test".to_string() };
+        let mut backend = DnsBackend::new(config, Duration::from_secs(1)).unwrap();
+        // the system roots don't know the stub
+        assert!(backend.lookup("www.example.test:443").await.is_err());
+
+        backend.tls_connector = Some(connector);
+        assert_eq!(backend.lookup("www.example.test:443").await.unwrap(), expected(443));
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_https() {
+        let (acceptor,connector) = test_tls();
+        let addr = http_stub(Some(acceptor)).await;
+        let config = DnsBackendConfig::Https { url: format!("https://dns.test:{}/dns-query", addr.port()), addresses: vec![addr] };
+        let mut backend = DnsBackend::new(config, Duration::from_secs(1)).unwrap();
+        backend.tls_connector = Some(connector);
+        assert_eq!(backend.lookup("www.example.test:443").await.unwrap(), expected(443));
+    }
+
+    #[test]
+    fn test_doh_host_header() {
+        let host = |url:&str| DohUrl::parse(url).unwrap().host_header();
+        assert_eq!(host("https://[2001:db8::53]/dns-query"), "[2001:db8::53]:443");
+        assert_eq!(host("https://[2001:db8::53]:8443/dns-query"), "[2001:db8::53]:8443");
+        assert_eq!(host("http://dns.example.org/dns-query"), "dns.example.org:80");
+        assert_eq!(DohUrl::parse("https://[2001:db8::53]/dns-query").unwrap().host, "2001:db8::53");
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let query = encode_query(7, "www.example.test.", TYPE_A).unwrap();
+        let answer = stub_answer(&query);
+        assert_eq!(decode_response(7, TYPE_A, &answer).unwrap(), Response::Answers(vec!["192.0.2.1".parse().unwrap()]));
+        assert!(decode_response(8, TYPE_A, &answer).is_err());
+        assert!(decode_response(7, TYPE_A, &answer[..answer.len() - 1]).is_err());
+
+        let mut truncated = answer.clone();
+        truncated[2] |= (FLAG_TRUNCATED >> 8) as u8;
+        assert_eq!(decode_response(7, TYPE_A, &truncated).unwrap(), Response::Truncated);
+
+        assert!(encode_query(7, "bad..name", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_decode_http_response() {
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert_eq!(decode_http_response(chunked).unwrap(), b"abcde");
+        assert!(decode_http_response(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::debug;
+use tokio::{io, net::TcpStream, time::sleep};
+
+use crate::{
+    configuration::{IpFamilyPolicy, SocketOptions, SourceConfig},
+    outbound,
+};
+
+/// Races connections to `addrs` (RFC 8305): the families are interleaved, IPv6 first
+/// unless `ip_family` prefers IPv4, a new attempt starts every `attempt_delay` or as soon as the previous one fails,
+/// and the first established connection wins. The losers are dropped.
+/// The caller bounds the whole race with its connection timeout.
+/// With a `source`, addresses its pool can't reach are left out.
+pub async fn connect(
+    addrs:&[SocketAddr],
+    ip_family:IpFamilyPolicy,
+    attempt_delay:Duration,
+    source:Option<&SourceConfig>,
+    options:&SocketOptions,
+) -> io::Result<(TcpStream,SocketAddr)> {
+    let addrs: Vec<SocketAddr> = addrs
+        .iter()
+        .filter(|addr| source.is_none_or(|source| source.allows(addr)))
+        .copied()
+        .collect();
+    let mut pending = interleave_families(&addrs, ip_family == IpFamilyPolicy::PreferIpv4).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr, source, options)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)));
+                }
+            }
+        }
+
+        tokio::select! {
+            Some((addr,result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream,addr)),
+                Err(e) => {
+                    debug!("Connection attempt to {} failed: {}", addr, e);
+                    last_error = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(attempt(addr, source, options));
+                    }
+                }
+            },
+            _ = sleep(attempt_delay), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(attempt(addr, source, options));
+                }
+            }
+        }
+    }
+}
+
+async fn attempt(addr:SocketAddr, source:Option<&SourceConfig>, options:&SocketOptions) -> (SocketAddr,io::Result<TcpStream>) {
+    (addr,outbound::connect_tcp(addr, source, options).await)
+}
+
+/// Alternates address families, starting with IPv6 (RFC 8305, section 4) or IPv4 if preferred,
+/// keeping the resolver's order within each family.
+fn interleave_families(addrs:&[SocketAddr], ipv4_first:bool) -> Vec<SocketAddr> {
+    let (mut preferred,mut other): (Vec<SocketAddr>,Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv4() == ipv4_first);
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        interleaved.extend(preferred.pop());
+        interleaved.extend(other.pop());
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let order = |addrs:&[SocketAddr],ipv4_first| -> Vec<String> {
+            interleave_families(addrs, ipv4_first).iter().map(|a| a.to_string()).collect()
+        };
+        assert_eq!(order(&addrs, false), ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+        assert_eq!(order(&addrs, true), ["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "[::2]:1", "[::3]:1"]);
+
+        // IPv6 first even when the resolver answered with IPv4 first
+        let mut ipv4_answered_first = addrs.clone();
+        ipv4_answered_first.rotate_left(3);
+        assert_eq!(order(&ipv4_answered_first, false), ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+    }
+
+    #[tokio::test]
+    async fn test_falls_through_dead_address() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let alive = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive_addr = alive.local_addr().unwrap();
+
+        let (_,winner) = connect(&[dead,alive_addr], IpFamilyPolicy::Any, Duration::from_secs(10), None, &SocketOptions::default()).await.unwrap();
+        assert_eq!(winner, alive_addr);
+    }
+
+    #[tokio::test]
+    async fn test_all_addresses_dead() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert!(connect(&[dead], IpFamilyPolicy::Any, Duration::from_millis(250), None, &SocketOptions::default()).await.is_err());
+        assert!(connect(&[], IpFamilyPolicy::Any, Duration::from_millis(250), None, &SocketOptions::default()).await.is_err());
+    }
+}
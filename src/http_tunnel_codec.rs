@@ -2,7 +2,7 @@ use std::{char::MAX, fmt::{self, Write}, str::Bytes, sync::Arc};
 
 use async_trait::async_trait;
 use clap::error::ErrorKind;
-use regex::{Regex, Split};
+use regex::Regex;
 use tokio_util::codec::{Decoder,Encoder};
 use bytes::BytesMut;
 use derive_builder::Builder;
@@ -10,7 +10,11 @@ use derive_builder::Builder;
 use log::debug;
 
 
-use crate::{proxy_target::Nugget, tunnel::{EstablishTunnelResult, TunnelCtx}};
+use crate::{
+    connect_udp::{match_uri_template, CONNECT_UDP_PROTOCOL, CONNECT_UDP_URI_TEMPLATE},
+    proxy_target::Nugget,
+    tunnel::{EstablishTunnelResult, TargetProtocol, TunnelCtx, TunnelTarget},
+};
 
 const MAX_HTTP_REQUEST_SIZE: usize = 16384;
 const REQUEST_END_MARKER:&[u8] = b"\r\n\r\n";
@@ -18,16 +22,21 @@ const REQUEST_END_MARKER:&[u8] = b"\r\n\r\n";
 pub struct HttpConnectRequest {
     uri:String,
     nugget:Option<Nugget>,
+    protocol:TargetProtocol,
 }
 
 impl HttpConnectRequest {
 
-    pub fn parse(http_request:&[u8]) -> Result<Self,EstablishTunnelResult> {
+    pub fn parse(http_request:&[u8], connect_udp_template:&str) -> Result<Self,EstablishTunnelResult> {
         HttpConnectRequest::precondition_size(http_request)?;
         HttpConnectRequest::precondition_legal_character(http_request)?;
 
         let as_string = String::from_utf8(http_request.to_vec()).expect("Contains only ASCII");
 
+        if let Some(connect_udp) = HttpConnectRequest::parse_connect_udp(&as_string, connect_udp_template)? {
+            return Ok(connect_udp);
+        }
+
         let mut lines = as_string.split("\r\n");
 
         let request_line = HttpConnectRequest::parse_request_line(lines
@@ -38,29 +47,72 @@ impl HttpConnectRequest {
         let has_nugget = request_line.3;
 
         if has_nugget {
+            // a plain request, forwarded as is to the host it names
             Ok(
                 Self {
-                    uri:HttpConnectRequest::extract_host_destination(lines, lines.1)
+                    uri:HttpConnectRequest::extract_host_destination(&mut lines, request_line.1)
                         .unwrap_or_else(|| request_line.1.to_string()),
-                        nugget: Some(Nugget::new(&self, other))
+                        nugget: Some(Nugget::new(http_request)),
+                        protocol: TargetProtocol::Tcp,
                 }
             )
         } else {
             Ok(
                 Self {
                     uri: request_line.1.to_string(),
-                    nugget:None
+                    nugget:None,
+                    protocol: TargetProtocol::Tcp,
                 }
             )
         }
     }
 
-    fn extract_host_destination(lines:&mut Split<&str>, endpoint:&str) -> Option<String> {
-        const HOST_HEADER:&str = "host:";
+    /// RFC 9298 over HTTP/1.1: `GET <uri template> HTTP/1.1` with `Upgrade: connect-udp`
+    /// and `Capsule-Protocol: ?1`.
+    /// Returns `None` for anything that is not a connect-udp upgrade.
+    fn parse_connect_udp(request:&str, template:&str) -> Result<Option<Self>,EstablishTunnelResult> {
+        let mut lines = request.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        if !request_line.starts_with("GET ") {
+            return Ok(None);
+        }
+
+        let headers = lines.filter_map(|line| line.split_once(':')).collect::<Vec<(&str,&str)>>();
+        let header = |wanted:&str| headers.iter().find(|(name,_)| name.trim().eq_ignore_ascii_case(wanted)).map(|(_,value)| value.trim());
+        if !header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case(CONNECT_UDP_PROTOCOL)) {
+            return Ok(None);
+        }
+        // RFC 9298 3.2, a structured boolean, parameters aside
+        if header("capsule-protocol").and_then(|value| value.split(';').next()).map(str::trim) != Some("?1") {
+            debug!("connect-udp request without Capsule-Protocol: ?1");
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+
+        let request_line = request_line.split(' ').collect::<Vec<&str>>();
+        if request_line.len() != 3 {
+            debug!("http header not well formed! , {:?}",request_line);
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+        HttpConnectRequest::check_version(request_line[2])?;
+
+        match match_uri_template(template, request_line[1]) {
+            Some(uri) => Ok(Some(Self {
+                uri,
+                nugget: None,
+                protocol: TargetProtocol::Udp,
+            })),
+            None => {
+                debug!("connect-udp request {} doesn't match template {}", request_line[1], template);
+                Err(EstablishTunnelResult::BadRequest)
+            }
+        }
+    }
 
+    fn extract_host_destination(lines:&mut std::str::Split<&str>, endpoint:&str) -> Option<String> {
         lines
-            .find(|line| line.starts_with(HOST_HEADER))
-            .map(|line| line[HOST_HEADER.len()..].trim())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name,_)| name.trim().eq_ignore_ascii_case("host"))
+            .map(|(_,host)| host.trim())
             .map(|host| {
                 let mut host = String::from(host);
                 if host.rfind(":").is_none() {
@@ -92,7 +144,7 @@ impl HttpConnectRequest {
     }
 
     fn parse_request_line(lines:&str) -> Result<(&str,&str,&str,bool), EstablishTunnelResult> {
-        let request_line =  lines.split(' ').collect::<Vec<&u8>>();
+        let request_line =  lines.split(' ').collect::<Vec<&str>>();
         HttpConnectRequest::precondition_well_formed(lines, &request_line)?;
 
         let method = request_line[0];
@@ -109,16 +161,16 @@ impl HttpConnectRequest {
     fn check_version(version:&str) -> Result<(),EstablishTunnelResult> {
         if version != "HTTP/1.1" {
             debug!("Failed Bad Version!: {}",version);
-            EstablishTunnelResult::BadRequest
+            Err(EstablishTunnelResult::BadRequest)
         } else {
             Ok(())
         }
     }
 
-    fn precondition_well_formed(http_request:&str,http_request_slice:&[&u8]) -> Result<(),EstablishTunnelResult> {
+    fn precondition_well_formed(http_request:&str,http_request_slice:&[&str]) -> Result<(),EstablishTunnelResult> {
         if http_request_slice.len() != 3 {
             debug!("http header not well formed! , {:?}",http_request);
-            EstablishTunnelResult::BadRequest
+            Err(EstablishTunnelResult::BadRequest)
         } else {
             Ok(())
         }
@@ -130,7 +182,7 @@ impl HttpConnectRequest {
             http_request.len(),
             MAX_HTTP_REQUEST_SIZE,
             );
-            EstablishTunnelResult::BadRequest
+            Err(EstablishTunnelResult::BadRequest)
         } else {
             Ok(())
         }
@@ -138,7 +190,7 @@ impl HttpConnectRequest {
 
     #[cfg(not(feature="plain_text"))]
     fn check_method(method:&str) -> Result<bool,EstablishTunnelResult> {
-        if !method=="CONNECT" {
+        if method!="CONNECT" {
             debug!("Warn! not CONNECT method operation not allowed! {}", method);
             Err(EstablishTunnelResult::Forbidden)
         } else {
@@ -158,6 +210,11 @@ impl HttpConnectRequest {
 pub struct HttpTunnelCodec {
     tunnel_ctx: TunnelCtx,
     enabled_targets: Regex,
+    #[builder(default = "CONNECT_UDP_URI_TEMPLATE.to_string()")]
+    connect_udp_template: String,
+    // the decoded request was a connect-udp upgrade, so success is `101`
+    #[builder(setter(skip))]
+    connect_udp: bool,
 }
 
 impl Decoder for HttpTunnelCodec {
@@ -169,20 +226,23 @@ impl Decoder for HttpTunnelCodec {
             return Ok(None)
         } 
 
-        match HttpConnectRequest::parse(src) {
+        match HttpConnectRequest::parse(&src.split(), &self.connect_udp_template) {
             Ok(parsed_request) => {
-                if self.enabled_targets.is_match(&parsed_request.uri) {
+                // TCP CONNECT and connect-udp targets share the same ACL
+                if !self.enabled_targets.is_match(&parsed_request.uri) {
                     debug!("Target {} is not allowed, only allowed is {}, CTX= {}",
                         parsed_request.uri,
                         self.enabled_targets,
                         self.tunnel_ctx
                         );
-                        Ok(EstablishTunnelResult::OperationNotAllowed)
+                        Err(EstablishTunnelResult::OperationNotAllowed)
                 } else {
+                    self.connect_udp = parsed_request.protocol == TargetProtocol::Udp;
                     Ok(Some(
                             HttpTunnelTargetBuilder::default()
                             .target(parsed_request.uri)
                             .nugget(parsed_request.nugget)
+                            .protocol(parsed_request.protocol)
                             .build()
                             .expect("HttpTunnelTargetBuilder is failed"),
                     ))
@@ -199,9 +259,20 @@ impl Encoder<EstablishTunnelResult> for HttpTunnelCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: EstablishTunnelResult, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if self.connect_udp && item == EstablishTunnelResult::Ok {
+            dst.extend_from_slice(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: {}\r\nCapsule-Protocol: ?1\r\n\r\n",
+                    CONNECT_UDP_PROTOCOL
+                ).as_bytes()
+            );
+            return Ok(());
+        }
+
         let (code,item) = match item {
             EstablishTunnelResult::Ok => (200,"OK"),
-            EstablishTunnelResult::OkWithNugget => Ok(()),
+            // the nugget went to the target, its response answers the client
+            EstablishTunnelResult::OkWithNugget => return Ok(()),
             EstablishTunnelResult::BadGateway => (502, "BAD_GATEWAY"),
             EstablishTunnelResult::Forbidden => (403, "FORBIDDEN"),
             EstablishTunnelResult::BadRequest => (400, "BAD_REQUEST"),
@@ -209,6 +280,7 @@ impl Encoder<EstablishTunnelResult> for HttpTunnelCodec {
             EstablishTunnelResult::TooManyRequest => (429, "TOO_MANY_REQUEST"),
             EstablishTunnelResult::RequestTimeout => (408, "REQUEST_TIMEOUT"),
             EstablishTunnelResult::GatewayTimeout => (504, "GATEWAY_TIMEOUT"),
+            EstablishTunnelResult::ServiceUnavailable => (503, "SERVICE_UNAVAILABLE"),
             EstablishTunnelResult::OperationNotAllowed => (405, "NOT_ALLOWED"),
         };
         dst.write_fmt(format_args!("HTTP/1.1 {} {}\r\n\r\n",code as u32,item)).map_err(|e| std::io::Error::from(std::io::ErrorKind::Other) )
@@ -221,6 +293,8 @@ impl Encoder<EstablishTunnelResult> for HttpTunnelCodec {
 pub struct HttpTunnelTarget {
     pub target: String,
     pub nugget: Option<Nugget>,
+    #[builder(default)]
+    pub protocol: TargetProtocol,
 
 }
 
@@ -244,6 +318,10 @@ impl TunnelTarget for HttpTunnelTarget {
 
     }
 
+    fn target_protocol(&self) -> TargetProtocol {
+        self.protocol
+    }
+
 }
 
 impl fmt::Display for HttpTunnelTarget {
@@ -252,14 +330,14 @@ impl fmt::Display for HttpTunnelTarget {
     }
 }
 
-// #[cfg(feature="plains_text")]
-// fn got_http_request(buffers:&BytesMut) -> bool {
-//     buffers.len() >= MAX_HTTP_REQUEST_SIZE ||
-//         buffers
-//         .windows(REQUEST_END_MARKER.len())
-//         .find(|w| *w == REQUEST_END_MARKER)
-//         .is_some()
-// }
+// a plain request may already carry part of its body after the head
+#[cfg(feature="plain_text")]
+fn got_http_request(buffers:&BytesMut) -> bool {
+    buffers.len() >= MAX_HTTP_REQUEST_SIZE ||
+        buffers
+        .windows(REQUEST_END_MARKER.len())
+        .any(|w| w == REQUEST_END_MARKER)
+}
 
 #[cfg(not(feature="plain_text"))]
 fn got_http_request(buffers:&BytesMut) -> bool {
@@ -269,3 +347,79 @@ fn got_http_request(buffers:&BytesMut) -> bool {
 
 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn codec() -> HttpTunnelCodec {
+        HttpTunnelCodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(r"^(origin\.example|192\.0\.2\.6):(80|443)$").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn decode(request:&str) -> Result<Option<HttpTunnelTarget>,EstablishTunnelResult> {
+        codec().decode(&mut BytesMut::from(request))
+    }
+
+    fn host(headers:&str, endpoint:&str) -> Option<String> {
+        HttpConnectRequest::extract_host_destination(&mut headers.split("\r\n"), endpoint)
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(host("HOST: origin.example:8080\r\n", "/"), Some("origin.example:8080".to_string()));
+        assert_eq!(host("Accept: */*\r\nhost:  origin.example \r\n", "/"), Some("origin.example:80".to_string()));
+        assert_eq!(host("Accept: */*\r\n", "http://origin.example/"), None);
+    }
+
+    #[test]
+    fn test_host_default_port() {
+        assert_eq!(host("Host: origin.example\r\n", "http://origin.example/"), Some("origin.example:80".to_string()));
+        assert_eq!(host("Host: origin.example\r\n", "HTTPS://origin.example/"), Some("origin.example:443".to_string()));
+        assert_eq!(host("Host: origin.example:8443\r\n", "https://origin.example/"), Some("origin.example:8443".to_string()));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut response = BytesMut::new();
+        // the target's response answers a forwarded request
+        codec().encode(EstablishTunnelResult::OkWithNugget, &mut response).unwrap();
+        assert!(response.is_empty());
+        codec().encode(EstablishTunnelResult::Ok, &mut response).unwrap();
+        assert_eq!(&response[..], b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn test_decode_connect() {
+        let target = decode("CONNECT origin.example:443 HTTP/1.1\r\nHost: origin.example:443\r\n\r\n").unwrap().unwrap();
+        assert_eq!((target.target.as_str(),target.nugget), ("origin.example:443",None));
+        assert_eq!(
+            decode("CONNECT blocked.example:443 HTTP/1.1\r\n\r\n").err(),
+            Some(EstablishTunnelResult::OperationNotAllowed)
+        );
+    }
+
+    #[cfg(feature = "plain_text")]
+    #[test]
+    fn test_decode_plain_request() {
+        let request = "GET http://origin.example/ HTTP/1.1\r\nhOsT: origin.example\r\n\r\n";
+        let target = decode(request).unwrap().unwrap();
+        assert_eq!(target.target, "origin.example:80");
+        assert_eq!(target.nugget, Some(Nugget::new(request.as_bytes())));
+    }
+
+    #[test]
+    fn test_connect_udp_capsule_protocol() {
+        let upgrade = "GET /.well-known/masque/udp/192.0.2.6/443/ HTTP/1.1\r\nHost: proxy.example\r\nConnection: Upgrade\r\nUpgrade: connect-udp\r\n";
+
+        let target = decode(&format!("{}Capsule-Protocol: ?1\r\n\r\n", upgrade)).unwrap().unwrap();
+        assert_eq!((target.target.as_str(),target.protocol), ("192.0.2.6:443",TargetProtocol::Udp));
+        assert!(decode(&format!("{}capsule-protocol: ?1;x\r\n\r\n", upgrade)).unwrap().is_some());
+
+        assert_eq!(decode(&format!("{}\r\n", upgrade)).err(), Some(EstablishTunnelResult::BadRequest));
+        assert_eq!(decode(&format!("{}Capsule-Protocol: ?0\r\n\r\n", upgrade)).err(), Some(EstablishTunnelResult::BadRequest));
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::{task::JoinHandle, time::{interval, timeout}};
+
+use crate::{
+    configuration::{BalanceStrategy, HealthCheckConfig, IpFamilyPolicy, TargetConnectionConfig, TcpBackend, TcpBalancerConfig},
+    happy_eyeballs,
+    proxy_target::{resolve_target, DnsResolver},
+};
+
+// points per unit of weight on the consistent hash ring
+const RING_POINTS_PER_WEIGHT: u32 = 160;
+
+/// Spreads TCP mode connections over the backends. A backend takes connections
+/// while the active health checks consider it healthy and it isn't ejected
+/// for consecutive connect failures. When no backend qualifies, all of them do:
+/// a possibly dead backend is better than refusing every client.
+#[derive(Clone)]
+pub struct LoadBalancer {
+    backends: Arc<Vec<Arc<Backend>>>,
+    config: TcpBalancerConfig,
+    next: Arc<AtomicUsize>,
+    // smooth weighted round-robin state, by backend index
+    current_weights: Arc<Mutex<Vec<i64>>>,
+    // (point, backend index), sorted by point
+    ring: Arc<Vec<(u64,usize)>>,
+}
+
+struct Backend {
+    config: TcpBackend,
+    healthy: AtomicBool,
+    // consecutive probes disagreeing with `healthy`
+    probe_streak: AtomicU32,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    active_connections: AtomicUsize,
+    total_connections: AtomicU64,
+    failed_connections: AtomicU64,
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct BackendStats {
+    pub address: String,
+    pub weight: u32,
+    pub healthy: bool,
+    pub ejected: bool,
+    pub active_connections: usize,
+    pub total_connections: u64,
+    pub failed_connections: u64,
+}
+
+/// A connection to a backend, counted as active until dropped.
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+    balancer: LoadBalancer,
+}
+
+impl LoadBalancer {
+    pub fn new(backends:Vec<TcpBackend>, config:TcpBalancerConfig) -> Self {
+        let backends: Vec<Arc<Backend>> = backends
+            .into_iter()
+            .map(|config| Arc::new(Backend {
+                config,
+                healthy: AtomicBool::new(true),
+                probe_streak: AtomicU32::new(0),
+                consecutive_failures: AtomicU32::new(0),
+                ejected_until: Mutex::new(None),
+                active_connections: AtomicUsize::new(0),
+                total_connections: AtomicU64::new(0),
+                failed_connections: AtomicU64::new(0),
+            }))
+            .collect();
+
+        Self {
+            current_weights: Arc::new(Mutex::new(vec![0;backends.len()])),
+            ring: Arc::new(build_ring(&backends)),
+            backends: Arc::new(backends),
+            config,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The backend for the next connection from `client`, `None` only without backends.
+    pub fn pick(&self, client:Option<IpAddr>) -> Option<BackendGuard> {
+        self.pick_except(client, None)
+    }
+
+    /// Like `pick`, but never the backend `failed` is connected to, `None` without another one.
+    pub fn pick_other(&self, client:Option<IpAddr>, failed:&BackendGuard) -> Option<BackendGuard> {
+        self.pick_except(client, Some(&failed.backend))
+    }
+
+    fn pick_except(&self, client:Option<IpAddr>, excluded:Option<&Arc<Backend>>) -> Option<BackendGuard> {
+        let now = Instant::now();
+        let eligible: Vec<usize> = (0..self.backends.len())
+            .filter(|i| excluded.is_none_or(|excluded| !Arc::ptr_eq(excluded, &self.backends[*i])))
+            .collect();
+        let mut candidates: Vec<usize> = eligible
+            .iter()
+            .copied()
+            .filter(|i| self.backends[*i].is_available(now))
+            .collect();
+        if candidates.is_empty() {
+            if eligible.is_empty() {
+                return None;
+            }
+            error!("No healthy TCP backend, trying all of them");
+            candidates = eligible;
+        }
+
+        let index = match self.config.strategy {
+            BalanceStrategy::RoundRobin => candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()],
+            BalanceStrategy::LeastConnections => {
+                // rotate the start, so ties don't always go to the first backend
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                let len = candidates.len();
+                (0..len)
+                    .map(|i| candidates[(offset + i) % len])
+                    .min_by_key(|i| self.backends[*i].active_connections.load(Ordering::Relaxed))
+                    .expect("Bug: no candidates")
+            }
+            BalanceStrategy::Weighted => self.pick_weighted(&candidates),
+            BalanceStrategy::ConsistentHash => match client {
+                Some(client) => self.pick_hashed(client, &candidates),
+                None => candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()],
+            },
+        };
+
+        let backend = self.backends[index].clone();
+        backend.active_connections.fetch_add(1, Ordering::Relaxed);
+        backend.total_connections.fetch_add(1, Ordering::Relaxed);
+        Some(BackendGuard {
+            backend,
+            balancer: self.clone(),
+        })
+    }
+
+    // nginx's smooth weighted round-robin: deterministic and without bursts
+    fn pick_weighted(&self, candidates:&[usize]) -> usize {
+        let mut current_weights = self.current_weights.lock().expect("Balancer lock is poisoned");
+        let total: i64 = candidates.iter().map(|i| self.backends[*i].config.weight as i64).sum();
+
+        for i in candidates {
+            current_weights[*i] += self.backends[*i].config.weight as i64;
+        }
+        let picked = *candidates
+            .iter()
+            .max_by_key(|i| (current_weights[**i], std::cmp::Reverse(**i)))
+            .expect("Bug: no candidates");
+        current_weights[picked] -= total;
+        picked
+    }
+
+    // the first point after the client's hash owned by a candidate, so only the
+    // clients of a backend that goes away move, to the next backends on the ring
+    fn pick_hashed(&self, client:IpAddr, candidates:&[usize]) -> usize {
+        let hash = hash_of(&client);
+        let start = self.ring.partition_point(|(point,_)| *point < hash);
+        (0..self.ring.len())
+            .map(|i| self.ring[(start + i) % self.ring.len()].1)
+            .find(|backend| candidates.contains(backend))
+            // zero weights have no points on the ring
+            .unwrap_or(candidates[0])
+    }
+
+    pub fn stats(&self) -> Vec<BackendStats> {
+        let now = Instant::now();
+        self.backends.iter().map(|backend| backend.stats(now)).collect()
+    }
+
+    /// Probes every backend each `interval`, resolving them like the targets.
+    pub fn spawn_health_checks<R>(&self, target_config:TargetConnectionConfig, mut dns_resolver:R) -> Option<JoinHandle<()>>
+    where
+        R: DnsResolver + Send + 'static,
+    {
+        let health_check = self.config.health_check.clone()?;
+        let balancer = self.clone();
+
+        Some(tokio::spawn(async move {
+            let mut ticks = interval(health_check.interval);
+            loop {
+                ticks.tick().await;
+                for backend in balancer.backends.iter() {
+                    let alive = probe(&backend.config.address, &health_check, &target_config, &mut dns_resolver).await;
+                    if backend.record_probe(alive, &health_check) {
+                        info!("TCP backends: {:?}", balancer.stats());
+                    }
+                }
+            }
+        }))
+    }
+}
+
+fn build_ring(backends:&[Arc<Backend>]) -> Vec<(u64,usize)> {
+    let mut ring: Vec<(u64,usize)> = backends
+        .iter()
+        .enumerate()
+        .flat_map(|(index,backend)| {
+            (0..backend.config.weight * RING_POINTS_PER_WEIGHT)
+                .map(move |replica| (hash_of(&(&backend.config.address, replica)), index))
+        })
+        .collect();
+    ring.sort_unstable();
+    ring
+}
+
+// stable for the life of the process, which is all the ring needs
+fn hash_of<T: Hash>(value:&T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn probe<R: DnsResolver>(
+    address:&str,
+    health_check:&HealthCheckConfig,
+    target_config:&TargetConnectionConfig,
+    dns_resolver:&mut R,
+) -> bool {
+    let connect = async {
+        let addrs = resolve_target(target_config, dns_resolver, address).await?;
+        happy_eyeballs::connect(&addrs, IpFamilyPolicy::Any, target_config.connection_attempt_delay, target_config.source.as_ref(), &target_config.socket_options).await
+    };
+    match timeout(health_check.timeout, connect).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("Health check of {} failed: {}", address, e);
+            false
+        }
+        Err(_) => {
+            debug!("Health check of {} timed out", address);
+            false
+        }
+    }
+}
+
+impl Backend {
+    fn is_available(&self, now:Instant) -> bool {
+        let ejected = self
+            .ejected_until
+            .lock()
+            .expect("Backend lock is poisoned")
+            .map(|until| until > now)
+            .unwrap_or(false);
+        self.healthy.load(Ordering::Relaxed) && !ejected
+    }
+
+    /// Returns true when the backend changed state.
+    fn record_probe(&self, alive:bool, health_check:&HealthCheckConfig) -> bool {
+        if alive == self.healthy.load(Ordering::Relaxed) {
+            self.probe_streak.store(0, Ordering::Relaxed);
+            return false;
+        }
+
+        let streak = self.probe_streak.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = if alive { health_check.healthy_threshold } else { health_check.unhealthy_threshold };
+        if streak < threshold {
+            return false;
+        }
+
+        self.probe_streak.store(0, Ordering::Relaxed);
+        self.healthy.store(alive, Ordering::Relaxed);
+        if alive {
+            info!("TCP backend {} is healthy again", self.config.address);
+        } else {
+            error!("TCP backend {} failed {} health checks, taking it out", self.config.address, streak);
+        }
+        true
+    }
+
+    fn stats(&self, now:Instant) -> BackendStats {
+        BackendStats {
+            address: self.config.address.clone(),
+            weight: self.config.weight,
+            healthy: self.healthy.load(Ordering::Relaxed),
+            ejected: !self.is_available(now) && self.healthy.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            failed_connections: self.failed_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl BackendGuard {
+    pub fn address(&self) -> &str {
+        &self.backend.config.address
+    }
+
+    pub fn report_success(&self) {
+        self.backend.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Ejects the backend for `ejection_time` after `max_connect_failures` in a row.
+    pub fn report_failure(&self) {
+        let backend = &self.backend;
+        backend.failed_connections.fetch_add(1, Ordering::Relaxed);
+
+        let failures = backend.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.balancer.config.max_connect_failures {
+            return;
+        }
+
+        backend.consecutive_failures.store(0, Ordering::Relaxed);
+        *backend.ejected_until.lock().expect("Backend lock is poisoned") =
+            Some(Instant::now() + self.balancer.config.ejection_time);
+        error!(
+            "TCP backend {} failed {} connections in a row, ejected for {:?}",
+            backend.config.address, failures, self.balancer.config.ejection_time
+        );
+        info!("TCP backends: {:?}", self.balancer.stats());
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{configuration::TunnelConfig, test_support::LiteralResolver};
+    use std::time::Duration;
+    use tokio::{net::TcpListener, time::sleep};
+
+    fn balancer(strategy:BalanceStrategy, weights:&[u32]) -> LoadBalancer {
+        let backends = weights
+            .iter()
+            .enumerate()
+            .map(|(i,weight)| TcpBackend { address: format!("10.0.0.{}:80", i + 1), weight: *weight })
+            .collect();
+        LoadBalancer::new(backends, TcpBalancerConfig { strategy, ..TcpBalancerConfig::default() })
+    }
+
+    fn picks(balancer:&LoadBalancer, count:usize) -> Vec<String> {
+        (0..count).map(|_| balancer.pick(None).unwrap().address().to_string()).collect()
+    }
+
+    #[test]
+    fn test_round_robin_and_weighted() {
+        let round_robin = balancer(BalanceStrategy::RoundRobin, &[1,1]);
+        assert_eq!(picks(&round_robin, 3), ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.1:80"]);
+
+        let weighted = balancer(BalanceStrategy::Weighted, &[3,1]);
+        let picked = picks(&weighted, 8);
+        assert_eq!(picked.iter().filter(|a| *a == "10.0.0.1:80").count(), 6);
+        // smooth: the light backend isn't starved until the end of the cycle
+        assert_eq!(picked[..4], ["10.0.0.1:80", "10.0.0.1:80", "10.0.0.2:80", "10.0.0.1:80"]);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let balancer = balancer(BalanceStrategy::LeastConnections, &[1,1,1]);
+        let first = balancer.pick(None).unwrap();
+        let second = balancer.pick(None).unwrap();
+        assert_ne!(first.address(), second.address());
+
+        let third = balancer.pick(None).unwrap();
+        drop(first);
+        // only the first backend has no connection now
+        assert_eq!(balancer.pick(None).unwrap().address(), "10.0.0.1:80");
+        assert_eq!(balancer.stats().iter().map(|s| s.active_connections).sum::<usize>(), 2);
+        drop((second,third));
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let balancer = balancer(BalanceStrategy::RoundRobin, &[1,1]);
+        for _ in 0..3 {
+            let guard = balancer.pick(None).unwrap();
+            if guard.address() == "10.0.0.1:80" {
+                guard.report_failure();
+            }
+            balancer.pick(None).unwrap();
+        }
+        // ejected after the third failure in a row
+        assert_eq!(picks(&balancer, 2), ["10.0.0.2:80", "10.0.0.2:80"]);
+        assert!(balancer.stats()[0].ejected);
+
+        let guard = balancer.pick(None).unwrap();
+        for _ in 0..3 {
+            guard.report_failure();
+        }
+        // nothing is left, everything is tried again
+        assert_eq!(picks(&balancer, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_pick_other() {
+        let hashed = balancer(BalanceStrategy::ConsistentHash, &[1,1,1]);
+        let client = Some(IpAddr::from([10,1,2,3]));
+        let failed = hashed.pick(client).unwrap();
+        failed.report_failure();
+        // not ejected yet, the hash would still pick it
+        assert_eq!(hashed.pick(client).unwrap().address(), failed.address());
+        assert_ne!(hashed.pick_other(client, &failed).unwrap().address(), failed.address());
+
+        let single = balancer(BalanceStrategy::RoundRobin, &[1]);
+        let failed = single.pick(None).unwrap();
+        assert!(single.pick_other(None, &failed).is_none());
+    }
+
+    #[test]
+    fn test_consistent_hash_is_sticky() {
+        let balancer = balancer(BalanceStrategy::ConsistentHash, &[1,1,1,1]);
+        let clients: Vec<IpAddr> = (0..1000u32).map(|i| IpAddr::from((0x0a00_0000 + i).to_be_bytes())).collect();
+        let pick = |client:IpAddr| balancer.pick(Some(client)).unwrap().address().to_string();
+
+        let before: Vec<String> = clients.iter().map(|client| pick(*client)).collect();
+        assert_eq!(before, clients.iter().map(|client| pick(*client)).collect::<Vec<String>>());
+        for backend in 1..=4 {
+            let share = before.iter().filter(|a| **a == format!("10.0.0.{}:80", backend)).count();
+            assert!(share > 150 && share < 350, "backend {} got {} clients", backend, share);
+        }
+
+        let ejected = balancer.pick(Some(clients[0])).unwrap();
+        for _ in 0..3 {
+            ejected.report_failure();
+        }
+        let after: Vec<String> = clients.iter().map(|client| pick(*client)).collect();
+        for (before,after) in before.iter().zip(&after) {
+            if before == ejected.address() {
+                assert_ne!(after, ejected.address());
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_active_health_checks() {
+        let alive = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let backends = vec![
+            TcpBackend { address: dead.to_string(), weight: 1 },
+            TcpBackend { address: alive.local_addr().unwrap().to_string(), weight: 1 },
+        ];
+        let config = TcpBalancerConfig {
+            health_check: Some(HealthCheckConfig {
+                interval: Duration::from_millis(20),
+                timeout: Duration::from_millis(200),
+                healthy_threshold: 1,
+                unhealthy_threshold: 2,
+            }),
+            ..TcpBalancerConfig::default()
+        };
+        let balancer = LoadBalancer::new(backends, config);
+        let checks = balancer
+            .spawn_health_checks(TunnelConfig::default().target_connection, LiteralResolver)
+            .unwrap();
+
+        sleep(Duration::from_millis(200)).await;
+        let stats = balancer.stats();
+        assert!(!stats[0].healthy);
+        assert!(stats[1].healthy);
+        let alive = alive.local_addr().unwrap().to_string();
+        assert_eq!(picks(&balancer, 2), [alive.clone(), alive]);
+        checks.abort();
+    }
+}
@@ -1,19 +1,55 @@
-use std::{io::{self, Error}, thread::spawn};
-use configuration::{ProxyConfiguration, ProxyMode};
-use http_tunnel_codec::{HttpTunnelCodec, HttpTunnelCodecBuilder, HttpTunnelTarget};
-use log::{info,error};
+use std::{collections::HashMap, io::{self, Error, ErrorKind}, net::SocketAddr, sync::Arc};
+use circuit_breaker::CircuitBreakers;
+use configuration::{ProxyConfiguration, ProxyMode, SocketOptions};
+use dns_backend::DnsBackend;
+use load_balancer::LoadBalancer;
+use http_tunnel_codec::{HttpTunnelCodec, HttpTunnelCodecBuilder, HttpTunnelTarget, HttpTunnelTargetBuilder};
+use log::{debug,info,error};
 use proxy_target::{DnsResolver, SimpleCachingDnsResolver, SimpleTcpConnector};
-use tokio::{io::{AsyncRead, AsyncWrite}, net::{self, TcpListener, TcpSocket}, sync::watch::error, task};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use socks5_codec::{encode_reply, socks5_auth_handshake, Socks5CodecBuilder, Socks5Command, Socks5Target};
+use socks5_udp::UdpAssociation;
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt}, net::{TcpListener, TcpSocket, TcpStream}, signal::unix::{signal, SignalKind}, time::timeout};
+use tokio_util::codec::Framed;
+use client_hello::PrefixedStream;
+use tls_server::ReloadableAcceptor;
 
+mod admin;
+mod circuit_breaker;
+mod client_hello;
 mod configuration;
 mod tunnel;
 mod http_tunnel_codec;
 mod relay;
 mod proxy_target;
-use rand::{thread_rng,Rng};
-use tunnel::TunnelCtxBuilder;
+mod proxy_protocol;
+mod connect_udp;
+mod dns_backend;
+mod happy_eyeballs;
+mod load_balancer;
+mod outbound;
+mod parent_proxy;
+mod socket_options;
+mod socks5_codec;
+mod socks5_udp;
+mod tls_client;
+mod tls_passthrough;
+mod tls_server;
+#[cfg(not(feature = "rustls"))]
+mod tls_openssl;
+#[cfg(feature = "rustls")]
+mod tls_rustls;
+#[cfg(test)]
+mod test_certs;
+#[cfg(test)]
+mod test_support;
+mod upstream_tls;
+use rand::Rng;
+use tunnel::{connect_to_target, relay_connections, ConnectionTunnel, EstablishTunnelResult, TunnelCtx, TunnelCtxBuilder};
 
 
+#[tokio::main]
 async fn main() -> io::Result<()> {
 
     let proxy_configuration = ProxyConfiguration::from_command_line().map_err(|e| {
@@ -21,28 +57,89 @@ async fn main() -> io::Result<()> {
         e
     })?;
 
+    let target_connection = &proxy_configuration.tunnel_config.target_connection;
+    let dns_backend = DnsBackend::new(target_connection.dns_backend.clone(), target_connection.dns_query_timeout)?;
     let dns_resolver = SimpleCachingDnsResolver::new(
-        proxy_configuration
-        .tunnel_config
-        .target_connection
-        .dns_cache_ttl,
+        dns_backend,
+        target_connection.dns_cache_ttl,
+        target_connection.dns_negative_cache_ttl,
+        target_connection.dns_cache_max_entries,
     );
+    if let Some(window) = target_connection.dns_refresh_ahead {
+        dns_resolver.spawn_refresh_ahead(window);
+    }
+    let circuit_breakers = CircuitBreakers::new(target_connection.circuit_breaker.clone());
+    // only TCP mode has backends
+    let balancer = match &proxy_configuration.mode {
+        ProxyMode::Tcp(backends) => Some(LoadBalancer::new(backends.clone(), proxy_configuration.tunnel_config.tcp_balancer.clone())),
+        _ => None,
+    };
+    watch_state_signals(dns_resolver.clone(), circuit_breakers.clone(), balancer.clone())?;
+    if let Some(admin_socket) = &proxy_configuration.tunnel_config.admin_socket {
+        admin::spawn(admin_socket, dns_resolver.clone(), circuit_breakers.clone(), balancer.clone())?;
+    }
 
     match &proxy_configuration.mode {
         ProxyMode::Http => {
-            serve_plain_text(&proxy_configuration,dns_resolver).await;
+            serve_plain_text(proxy_configuration,dns_resolver,circuit_breakers).await?;
+        }
+        ProxyMode::Https(identities) => {
+            let acceptor = ReloadableAcceptor::new(identities)?;
+            acceptor.spawn_reloads()?;
+            serve_tls(proxy_configuration.clone(),acceptor,dns_resolver,circuit_breakers).await?;
+        }
+        ProxyMode::Tcp(_) => {
+            let balancer = balancer.expect("Bug: TCP mode without a balancer");
+            serve_tcp(proxy_configuration.clone(),balancer,dns_resolver,circuit_breakers).await?;
+        }
+        ProxyMode::Socks5(users) => {
+            serve_socks5(proxy_configuration.clone(),users.clone(),dns_resolver,circuit_breakers).await?;
+        }
+        ProxyMode::TlsPassthrough(port) => {
+            serve_tls_passthrough(proxy_configuration.clone(),*port,dns_resolver,circuit_breakers).await?;
         }
     }
 
+    Ok(())
+}
 
+/// SIGUSR1 logs the DNS cache, the circuit breakers and the TCP backends, SIGUSR2 flushes the DNS cache.
+fn watch_state_signals(dns_resolver:SimpleCachingDnsResolver, circuit_breakers:CircuitBreakers, balancer:Option<LoadBalancer>) -> io::Result<()> {
+    let mut dump = signal(SignalKind::user_defined1())?;
+    let mut flush = signal(SignalKind::user_defined2())?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(_) = dump.recv() => {
+                    for entry in dns_resolver.entries() {
+                        info!("DNS cache: {} -> {:?}, expires in {:?}, idle for {:?}",
+                            entry.target, entry.addrs, entry.expires_in, entry.idle_for);
+                    }
+                    for breaker in circuit_breakers.stats() {
+                        info!("Circuit breaker: {} is {:?}, {} recent failures, open for {:?}",
+                            breaker.host, breaker.state, breaker.recent_failures, breaker.open_for);
+                    }
+                    for backend in balancer.iter().flat_map(LoadBalancer::stats) {
+                        info!("TCP backend: {:?}", backend);
+                    }
+                }
+                Some(_) = flush.recv() => {
+                    dns_resolver.flush_all();
+                }
+                else => break,
+            }
+        }
+    });
+    Ok(())
 }
 
 async fn start_listening_tcp(config: &ProxyConfiguration) -> Result<TcpListener,Error> {
     let bind_address = &config.bind_address;
 
-    match TcpListener::bind(&bind_address).await {
+    match bind_listener(bind_address, &config.tunnel_config.client_connection.socket_options).await {
         Ok(listener) => {
-            info!("Succes to bind address {bind_address}: {listener}");
+            info!("Succes to bind address {bind_address}");
             Ok(listener)
         }
         Err(e) => {
@@ -52,65 +149,398 @@ async fn start_listening_tcp(config: &ProxyConfiguration) -> Result<TcpListener,
     }
 }
 
-async fn serve_plain_text(proxy_configuration:ProxyConfiguration, dns_resolver:DnsResolver) -> io::Result<()> {
+async fn bind_listener(bind_address:&str, options:&SocketOptions) -> io::Result<TcpListener> {
+    if !options.fast_open {
+        return TcpListener::bind(bind_address).await;
+    }
+
+    let addr = tokio::net::lookup_host(bind_address)
+        .await?
+        .next()
+        .ok_or_else(|| Error::from(ErrorKind::AddrNotAvailable))?;
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    socket_options::enable_fast_open_listen(&socket)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+// a connection that can't be tuned is still served, the failure is logged
+fn tune_accepted(stream:&TcpStream, config:&ProxyConfiguration) {
+    let ipv4 = stream.local_addr().map(|addr| addr.is_ipv4()).unwrap_or(true);
+    socket_options::apply_tcp(stream, &config.tunnel_config.client_connection.socket_options, ipv4).unwrap_or_default();
+}
+
+fn new_tunnel_ctx(stream:&TcpStream) -> TunnelCtx {
+    TunnelCtxBuilder::default()
+        .id(rand::rng().random::<u128>())
+        .client_addr(stream.peer_addr().ok())
+        .local_addr(stream.local_addr().ok())
+        .build()
+        .expect("Tunnelctxbuilder: failed")
+}
+
+async fn serve_plain_text(config:ProxyConfiguration, dns_resolver:SimpleCachingDnsResolver, circuit_breakers:CircuitBreakers) -> io::Result<()> {
     let listener = start_listening_tcp(&config).await?;
 
     loop {
         let socket = listener.accept().await;
 
         let dns_resolver_ref = dns_resolver.clone();
+        let circuit_breakers_ref = circuit_breakers.clone();
 
         match socket{
             Ok((stream,_)) => {
-                stream.nodelay().unwrap_or_default();
+                tune_accepted(&stream, &config);
                 let config = config.clone();
+                let ctx = new_tunnel_ctx(&stream);
                 // handle accepted connections asynchronously
                 tokio::spawn(async move {
-                    tunnel_stream(&config, client_connection, dns_resolver)
+                    tunnel_stream(&config, stream, ctx, dns_resolver_ref, circuit_breakers_ref).await
+                });
+            }
+            Err(e) => error!("Failed to accept connection: {}", e),
+        }
+
+    }
+
+}
+
+async fn serve_tls(config:ProxyConfiguration, acceptor:ReloadableAcceptor, dns_resolver:SimpleCachingDnsResolver, circuit_breakers:CircuitBreakers) -> io::Result<()> {
+    let listener = start_listening_tcp(&config).await?;
 
-                })  
+    loop {
+        let socket = listener.accept().await;
 
+        let dns_resolver_ref = dns_resolver.clone();
+        let circuit_breakers_ref = circuit_breakers.clone();
+
+        match socket{
+            Ok((stream,_)) => {
+                tune_accepted(&stream, &config);
+                let config = config.clone();
+                let acceptor = acceptor.clone();
+                let ctx = new_tunnel_ctx(&stream);
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let initiation_timeout = config.tunnel_config.client_connection.initiation_timeout;
+                    let (client_hello,server_name) = match timeout(initiation_timeout, client_hello::read_client_hello(&mut stream)).await {
+                        Ok(Ok(client_hello)) => client_hello,
+                        Ok(Err(e)) => {
+                            error!("Client didn't start with a valid TLS ClientHello: {}, CTX={}", e, ctx);
+                            return Ok(());
+                        }
+                        Err(_) => {
+                            error!("Client didn't send a TLS ClientHello in time, CTX={}", ctx);
+                            return Ok(());
+                        }
+                    };
+                    debug!("Client asked for server name {:?}, CTX={}", server_name, ctx);
+                    let acceptors = acceptor.current();
+                    let acceptor = acceptors.select(server_name.as_deref());
+                    let ctx = ctx.with_server_name(server_name);
+
+                    // the ClientHello alone doesn't bound the handshake, a client may stall after it
+                    match timeout(initiation_timeout, tls_server::accept(acceptor, PrefixedStream::new(client_hello, stream))).await {
+                        Ok(Ok(tls_stream)) => {
+                            // the certificate identity is the user ACLs and tenant settings apply to
+                            let user = acceptors.client_identity(&tls_stream);
+                            debug!("Client authenticated as {:?}, CTX={}", user, ctx);
+                            tunnel_stream(&config, tls_stream, ctx.with_user(user), dns_resolver_ref, circuit_breakers_ref).await
+                        }
+                        Ok(Err(e)) => {
+                            error!("Client opened a TCP connection but TLS handshake failed: {}, CTX={}", e, ctx);
+                            Ok(())
+                        }
+                        Err(_) => {
+                            error!("Client didn't complete the TLS handshake in time, CTX={}", ctx);
+                            Ok(())
+                        }
+                    }
+                });
             }
+            Err(e) => error!("Failed to accept connection: {}", e),
         }
+    }
+}
+
+async fn serve_tcp(config:ProxyConfiguration, balancer:LoadBalancer, dns_resolver:SimpleCachingDnsResolver, circuit_breakers:CircuitBreakers) -> io::Result<()> {
+    let listener = start_listening_tcp(&config).await?;
+
+    balancer.spawn_health_checks(config.tunnel_config.target_connection.clone(), dns_resolver.clone());
+
+    loop {
+        let socket = listener.accept().await;
+
+        let dns_resolver_ref = dns_resolver.clone();
+        let circuit_breakers_ref = circuit_breakers.clone();
+
+        match socket{
+            Ok((mut stream,_)) => {
+                tune_accepted(&stream, &config);
+                let config = config.clone();
+                let balancer = balancer.clone();
+                let ctx = new_tunnel_ctx(&stream);
+
+                tokio::spawn(async move {
+                    let ctx = if config.tunnel_config.tcp_balancer.accept_proxy_protocol {
+                        let initiation_timeout = config.tunnel_config.client_connection.initiation_timeout;
+                        match timeout(initiation_timeout, proxy_protocol::read_header(&mut stream)).await {
+                            Ok(Ok(Some(source))) => ctx.with_client_addr(Some(source)),
+                            Ok(Ok(None)) => ctx,
+                            Ok(Err(e)) => {
+                                error!("Failed to read PROXY header: {}, CTX={}", e, ctx);
+                                return Ok(());
+                            }
+                            Err(_) => {
+                                error!("Timeout reading PROXY header, CTX={}", ctx);
+                                return Ok(());
+                            }
+                        }
+                    } else {
+                        ctx
+                    };
+                    let client = ctx.client_addr().map(|addr| addr.ip());
+                    let Some(mut backend) = balancer.pick(client) else {
+                        error!("No TCP backend configured, CTX={}", ctx);
+                        return Ok(());
+                    };
+
+                    let target_config = config.tunnel_config.target_connection.clone();
+                    let mut connector: SimpleTcpConnector<HttpTunnelTarget, SimpleCachingDnsResolver> =
+                        SimpleTcpConnector::new(dns_resolver_ref, target_config.clone(), ctx.clone(), circuit_breakers_ref);
 
+                    let mut retried = false;
+                    let destination = loop {
+                        let target = HttpTunnelTargetBuilder::default()
+                            .target(backend.address().to_string())
+                            .nugget(None)
+                            .build()
+                            .expect("HttpTunnelTargetBuilder failed");
+
+                        match connect_to_target(&mut connector, &target, &target_config, &ctx).await {
+                            Ok(destination) => {
+                                backend.report_success();
+                                break destination;
+                            }
+                            Err(_) => {
+                                backend.report_failure();
+                                // one more backend at most, the client is waiting
+                                let other = if retried { None } else { balancer.pick_other(client, &backend) };
+                                let Some(other) = other else {
+                                    return Ok(());
+                                };
+                                debug!("Retrying on TCP backend {}, CTX={}", other.address(), ctx);
+                                retried = true;
+                                backend = other;
+                            }
+                        }
+                    };
+
+                    // the backend guard counts the connection as active until the relay ends
+                    relay_connections(
+                        stream,
+                        destination,
+                        ctx,
+                        config.tunnel_config.client_connection.relay_policy,
+                        target_config.relay_policy,
+                    ).await?;
+                    drop(backend);
+                    Ok::<(),io::Error>(())
+                });
+            }
+            Err(e) => error!("Failed to accept connection: {}", e),
+        }
     }
+}
+
+async fn serve_tls_passthrough(config:ProxyConfiguration, port:u16, dns_resolver:SimpleCachingDnsResolver, circuit_breakers:CircuitBreakers) -> io::Result<()> {
+    let listener = start_listening_tcp(&config).await?;
 
+    loop {
+        let socket = listener.accept().await;
+
+        let dns_resolver_ref = dns_resolver.clone();
+        let circuit_breakers_ref = circuit_breakers.clone();
+
+        match socket{
+            Ok((stream,_)) => {
+                tune_accepted(&stream, &config);
+                let config = config.clone();
+                let ctx = new_tunnel_ctx(&stream);
+                tokio::spawn(async move {
+                    tls_passthrough::tunnel_stream(
+                        &config.tunnel_config.client_connection,
+                        &config.tunnel_config.target_connection,
+                        stream,
+                        port,
+                        ctx,
+                        dns_resolver_ref,
+                        circuit_breakers_ref,
+                    ).await
+                });
+            }
+            Err(e) => error!("Failed to accept connection: {}", e),
+        }
+    }
 }
 
-async fn start_listening_tcp(config:ProxyConfiguration) -> Result<TcpListener,Error> {
-    let address = &config.bind_address;
-    
-    match TcpListener::bind(address).await {
-        Ok(s) => {
-            info!("Serving request on: {bind_address}");
-            Ok(s)
-        },
-        Err(e) => {
-            // error! is the highest priority on log crate and trace! is the lowest!
-            error!("Failed to bind to address: {}");
-            Err(e)
+async fn serve_socks5(config:ProxyConfiguration, users:HashMap<String,String>, dns_resolver:SimpleCachingDnsResolver, circuit_breakers:CircuitBreakers) -> io::Result<()> {
+    let listener = start_listening_tcp(&config).await?;
+    let users = Arc::new(users);
+
+    loop {
+        let socket = listener.accept().await;
+
+        let dns_resolver_ref = dns_resolver.clone();
+        let circuit_breakers_ref = circuit_breakers.clone();
+
+        match socket{
+            Ok((mut stream,_)) => {
+                tune_accepted(&stream, &config);
+                let config = config.clone();
+                let users = users.clone();
+                let ctx = new_tunnel_ctx(&stream);
+
+                tokio::spawn(async move {
+                    let handshake = timeout(
+                        config.tunnel_config.client_connection.initiation_timeout,
+                        socks5_auth_handshake(&mut stream, &users, &ctx),
+                    ).await;
+
+                    match handshake {
+                        Ok(Ok(user)) => socks5_tunnel_stream(&config, stream, ctx.with_user(user), dns_resolver_ref, circuit_breakers_ref).await,
+                        Ok(Err(e)) => {
+                            error!("SOCKS5 handshake failed: {}, CTX={}", e, ctx);
+                            Ok(())
+                        }
+                        Err(_) => {
+                            error!("SOCKS5 handshake timed out, CTX={}", ctx);
+                            Ok(())
+                        }
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept connection: {}", e),
         }
     }
 }
 
-async fn tunnel_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+async fn socks5_tunnel_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static, R: DnsResolver + Clone + Send + Sync + 'static>(
     config: &ProxyConfiguration,
     client_connection:C,
-    dns_resolver:DnsResolver
+    ctx:TunnelCtx,
+    dns_resolver:R,
+    circuit_breakers:CircuitBreakers,
 ) -> io::Result<()> {
-    let ctx = TunnelCtxBuilder::default()
-        .id(thread_rng().r#gen::<u128>())
+    let client_config = &config.tunnel_config.client_connection;
+    let target_config = &config.tunnel_config.target_connection;
+
+    let codec = Socks5CodecBuilder::default()
+        .tunnel_ctx(ctx.clone())
+        .enabled_targets(target_config.allowed_targets_for(ctx.user(), ctx.server_name()).clone())
         .build()
-        .expect("Tunnelctxbuilder: failed");
+        .expect("Socks5CodecBuilder failed");
+    let mut framed = Framed::new(client_connection, codec);
+
+    // unlike HTTP CONNECT, the request decides whether we relay a stream or datagrams
+    let target = match timeout(client_config.initiation_timeout, framed.next()).await {
+        Ok(Some(Ok(target))) => target,
+        Ok(Some(Err(e))) => {
+            framed.send(e).await?;
+            return Ok(());
+        }
+        Ok(None) => return Ok(()),
+        Err(_) => {
+            framed.send(EstablishTunnelResult::RequestTimeout).await?;
+            return Ok(());
+        }
+    };
+
+    match target.command {
+        Socks5Command::Connect => {
+            let mut connector: SimpleTcpConnector<Socks5Target, R> = SimpleTcpConnector::new(
+                dns_resolver,
+                target_config.clone(),
+                ctx.clone(),
+                circuit_breakers,
+            );
+
+            match connect_to_target(&mut connector, &target, target_config, &ctx).await {
+                Ok(target_connection) => {
+                    timeout(client_config.initiation_timeout, framed.send(EstablishTunnelResult::Ok)).await??;
+                    relay_connections(
+                        framed.into_inner(),
+                        target_connection,
+                        ctx,
+                        client_config.relay_policy.clone(),
+                        target_config.relay_policy.clone(),
+                    ).await?;
+                }
+                Err(e) => framed.send(e).await?,
+            }
+        }
+        Socks5Command::UdpAssociate => {
+            let association = UdpAssociation::bind(
+                ctx.clone(),
+                target_config.clone(),
+                dns_resolver,
+                client_config.relay_policy.clone(),
+            ).await;
 
-    let codex: HttpTunnelCodec = HttpTunnelCodecBuilder::default()
-        .tunnel_ctx(ctx)
+            let mut client_connection = framed.into_inner();
+            let mut reply = BytesMut::new();
+            match association {
+                Ok(association) => {
+                    encode_reply(&EstablishTunnelResult::Ok, association.local_addr()?, &mut reply);
+                    client_connection.write_all(&reply).await?;
+                    info!("UDP association for {} on {}, CTX={}", target, association.local_addr()?, ctx);
+                    association.relay(client_connection).await?;
+                }
+                Err(e) => {
+                    error!("Failed to bind UDP association: {}, CTX={}", e, ctx);
+                    encode_reply(&EstablishTunnelResult::ServerError, SocketAddr::from(([0,0,0,0],0)), &mut reply);
+                    client_connection.write_all(&reply).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn tunnel_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static, R: DnsResolver + Clone + Send + Sync + 'static>(
+    config: &ProxyConfiguration,
+    client_connection:C,
+    ctx:TunnelCtx,
+    dns_resolver:R,
+    circuit_breakers:CircuitBreakers,
+) -> io::Result<()> {
+    let codec: HttpTunnelCodec = HttpTunnelCodecBuilder::default()
+        .tunnel_ctx(ctx.clone())
         .enabled_targets(
-            config.tunnel_config.target_connection.allowed_targets.clone()
+            config.tunnel_config.target_connection.allowed_targets_for(ctx.user(), ctx.server_name()).clone()
         )
         .build()
         .expect("HttpTunnelCodecBuilder failed");
 
-    let connector: SimpleTcpConnector<HttpTunnelTarget, DnsResolver>
+    let connector: SimpleTcpConnector<HttpTunnelTarget, R> = SimpleTcpConnector::new(
+        dns_resolver,
+        config.tunnel_config.target_connection.clone(),
+        ctx.clone(),
+        circuit_breakers,
+    );
+
+    ConnectionTunnel::new(
+        codec,
+        connector,
+        client_connection,
+        config.tunnel_config.client_connection.clone(),
+        config.tunnel_config.target_connection.clone(),
+        ctx,
+    )
+    .start()
+    .await?;
 
+    Ok(())
 }
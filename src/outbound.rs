@@ -0,0 +1,105 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::AsFd,
+};
+
+use log::{debug, error};
+use socket2::SockRef;
+use tokio::{
+    io,
+    net::{TcpSocket, TcpStream, UdpSocket},
+};
+
+use crate::{
+    configuration::{SocketOptions, SourceConfig},
+    socket_options,
+};
+
+/// Connects to `target` from the next address of `source`'s pool and its interface,
+/// the socket is tuned with `options` before the SYN goes out.
+pub async fn connect_tcp(target:SocketAddr, source:Option<&SourceConfig>, options:&SocketOptions) -> io::Result<TcpStream> {
+    let socket = if target.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket_options::apply_tcp(&socket, options, target.is_ipv4())?;
+    if options.fast_open {
+        socket_options::enable_fast_open_connect(&socket)?;
+    }
+
+    if let Some(source) = source {
+        bind_device(&socket, source)?;
+        if let Some(local) = source.next_address(&target) {
+            socket.bind(SocketAddr::new(local,0)).map_err(|e| {
+                error!("Failed to bind outbound socket to {}: {}", local, e);
+                e
+            })?;
+            debug!("Connecting to {} from {}", target, local);
+        }
+    }
+    socket.connect(target).await
+}
+
+/// UDP socket connected to `target`, bound like [`connect_tcp`].
+pub async fn connect_udp(target:SocketAddr, source:Option<&SourceConfig>, options:&SocketOptions) -> io::Result<UdpSocket> {
+    let local = match source.and_then(|source| source.next_address(&target)) {
+        Some(local) => local,
+        None if target.is_ipv4() => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        None => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local,0)).await.map_err(|e| {
+        error!("Failed to bind outbound UDP socket to {}: {}", local, e);
+        e
+    })?;
+    socket_options::apply_udp(&socket, options, target.is_ipv4())?;
+    if let Some(source) = source {
+        bind_device(&socket, source)?;
+    }
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device<S: AsFd>(socket:&S, source:&SourceConfig) -> io::Result<()> {
+    match &source.interface {
+        Some(interface) => SockRef::from(socket).bind_device(Some(interface.as_bytes())).map_err(|e| {
+            error!("Failed to bind outbound socket to interface {}: {}", interface, e);
+            e
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device<S: AsFd>(_socket:&S, source:&SourceConfig) -> io::Result<()> {
+    match &source.interface {
+        Some(interface) => {
+            error!("Binding to interface {} is not supported on this platform", interface);
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_binds_pool_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let source = SourceConfig {
+            addresses: vec!["127.0.0.2".parse().unwrap(), "127.0.0.3".parse().unwrap(), "::1".parse().unwrap()],
+            ..Default::default()
+        };
+
+        for expected in ["127.0.0.2", "127.0.0.3", "127.0.0.2"] {
+            let _stream = connect_tcp(target, Some(&source), &SocketOptions::default()).await.unwrap();
+            let (_,peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip().to_string(), expected);
+        }
+
+        let udp_target = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let socket = connect_udp(udp_target, Some(&source), &SocketOptions::default()).await.unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip().to_string(), "127.0.0.3");
+    }
+}
@@ -0,0 +1,244 @@
+use std::net::SocketAddr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, error, info};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+use crate::{
+    configuration::{IpFamilyPolicy, ParentProxyConfig, ParentProxyProtocol, TargetConnectionConfig},
+    happy_eyeballs,
+    proxy_target::{resolve_target, DnsResolver, TargetStream},
+    socks5_codec::socks5_connect,
+    tls_client,
+    tunnel::TunnelCtx,
+};
+
+const MAX_RESPONSE_HEAD_SIZE: usize = 16384;
+
+/// Tunnels to `target` through the first parent proxy that accepts the CONNECT.
+/// Parents that can't be reached or fail are skipped, a refusal by the rules or a 429 is an answer
+/// about the target and ends the failover. The parent's verdict is reported as the
+/// error kind: `PermissionDenied`, `QuotaExceeded`, `TimedOut` or a generic failure.
+pub async fn connect<R: DnsResolver>(
+    parents:&[ParentProxyConfig],
+    target:&str,
+    target_config:&TargetConnectionConfig,
+    ip_family:IpFamilyPolicy,
+    dns_resolver:&mut R,
+    ctx:&TunnelCtx,
+) -> io::Result<TargetStream> {
+    let mut last_error = io::Error::from(io::ErrorKind::AddrNotAvailable);
+
+    for parent in parents {
+        let attempt = connect_parent(parent, target, target_config, ip_family, dns_resolver, ctx);
+        match timeout(parent.connect_timeout, attempt).await {
+            Ok(Ok(stream)) => {
+                info!("Connected to {} through parent proxy {}, CTX={}", target, parent.address, ctx);
+                return Ok(stream);
+            }
+            Ok(Err(e)) if matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::QuotaExceeded) => {
+                return Err(e);
+            }
+            Ok(Err(e)) => {
+                error!("Parent proxy {} failed to connect to {}: {}, CTX={}", parent.address, target, e, ctx);
+                last_error = e;
+            }
+            Err(_) => {
+                error!("Timeout connecting to {} through parent proxy {}, CTX={}", target, parent.address, ctx);
+                last_error = io::Error::from(io::ErrorKind::TimedOut);
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+async fn connect_parent<R: DnsResolver>(
+    parent:&ParentProxyConfig,
+    target:&str,
+    target_config:&TargetConnectionConfig,
+    ip_family:IpFamilyPolicy,
+    dns_resolver:&mut R,
+    ctx:&TunnelCtx,
+) -> io::Result<TargetStream> {
+    // resolved before connecting to the parent, a failure here doesn't cost a connection
+    let resolved = match parent.protocol {
+        ParentProxyProtocol::Socks5 if !parent.remote_dns => {
+            let addrs = ip_family.apply(resolve_target(target_config, dns_resolver, target).await?);
+            Some(*addrs.first().ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?)
+        }
+        _ => None,
+    };
+
+    let addrs = ip_family.apply(resolve_target(target_config, dns_resolver, &parent.address).await?);
+    // the parent is reached from the source the target would have been
+    let source = target_config.source_for(target, ctx.user());
+    let options = target_config.socket_options_for(target);
+    let (mut stream,addr) = happy_eyeballs::connect(&addrs, ip_family, target_config.connection_attempt_delay, source, options).await?;
+    debug!("Connected to parent proxy {} via {}, CTX={}", parent.address, addr, ctx);
+
+    if !parent.tls {
+        handshake(&mut stream, parent, target, resolved).await?;
+        return Ok(TargetStream::Tcp(stream));
+    }
+
+    let server_name = match &parent.tls_server_name {
+        Some(server_name) => server_name.as_str(),
+        None => host_of(&parent.address),
+    };
+    let connector = tls_client::system_connector().inspect_err(|_| error!("No TLS connector for parent proxy {}, CTX={}", parent.address, ctx))?;
+    let mut stream = tls_client::connect(&connector, server_name, stream).await.map_err(|e| {
+        error!("TLS handshake with parent proxy {} failed: {}, CTX={}", parent.address, e, ctx);
+        io::Error::from(io::ErrorKind::ConnectionAborted)
+    })?;
+
+    handshake(&mut stream, parent, target, resolved).await?;
+    Ok(TargetStream::Tls(Box::new(stream)))
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream:&mut S,
+    parent:&ParentProxyConfig,
+    target:&str,
+    resolved:Option<SocketAddr>,
+) -> io::Result<()> {
+    match parent.protocol {
+        ParentProxyProtocol::Http => http_connect(stream, parent, target).await,
+        ParentProxyProtocol::Socks5 => {
+            let credentials = match (&parent.username,&parent.password) {
+                (Some(username),Some(password)) => Some((username.as_str(),password.as_str())),
+                _ => None,
+            };
+            socks5_connect(stream, credentials, target, resolved).await
+        }
+    }
+}
+
+async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(stream:&mut S, parent:&ParentProxyConfig, target:&str) -> io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let (Some(username),Some(password)) = (&parent.username,&parent.password) {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // byte by byte, whatever follows the head already belongs to the target
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD_SIZE {
+            error!("Parent proxy {} response is too large", parent.address);
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    let status = std::str::from_utf8(&head)
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| {
+            error!("Bad response from parent proxy {}", parent.address);
+            io::Error::from(io::ErrorKind::InvalidData)
+        })?;
+
+    debug!("Parent proxy {} answered {} for {}", parent.address, status, target);
+    match status {
+        200..=299 => Ok(()),
+        403 => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        407 => {
+            error!("Parent proxy {} rejected the credentials", parent.address);
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        }
+        429 => Err(io::Error::from(io::ErrorKind::QuotaExceeded)),
+        408 | 504 => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        _ => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+    }
+}
+
+pub(crate) fn host_of(address:&str) -> &str {
+    let host = address.rsplit_once(':').map(|(host,_)| host).unwrap_or(address);
+    host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{configuration::TunnelConfig, test_support::LiteralResolver};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Answers every CONNECT with `status`, then echoes and records the request head.
+    async fn stub_parent(status:&'static str) -> (String,tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (heads,received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream,_) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let _ = heads.send(String::from_utf8(head).unwrap());
+                stream.write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes()).await.unwrap();
+                let (mut reader,mut writer) = stream.split();
+                let _ = io::copy(&mut reader, &mut writer).await;
+            }
+        });
+        (addr,received)
+    }
+
+    fn parent(address:&str) -> ParentProxyConfig {
+        ParentProxyConfig {
+            address: address.to_string(),
+            protocol: ParentProxyProtocol::Http,
+            remote_dns: true,
+            tls: false,
+            tls_server_name: None,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+            connect_timeout: Duration::from_secs(1),
+        }
+    }
+
+    async fn connect_through(parents:&[ParentProxyConfig]) -> io::Result<TargetStream> {
+        let target_config = TunnelConfig::default().target_connection;
+        connect(parents, "example.com:443", &target_config, IpFamilyPolicy::Any, &mut LiteralResolver, &TunnelCtx::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_next_parent() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let (failing,_) = stub_parent("502 Bad Gateway").await;
+        let (working,mut heads) = stub_parent("200 Connection established").await;
+
+        let mut stream = connect_through(&[parent(&dead), parent(&failing), parent(&working)]).await.unwrap();
+
+        let head = heads.recv().await.unwrap();
+        assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8;4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_parent_verdict_is_final() {
+        let (forbidding,_) = stub_parent("403 Forbidden").await;
+        let (working,_) = stub_parent("200 OK").await;
+
+        let result = connect_through(&[parent(&forbidding), parent(&working)]).await;
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+
+        let (limiting,_) = stub_parent("429 Too Many Requests").await;
+        let result = connect_through(&[parent(&limiting)]).await;
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::QuotaExceeded));
+    }
+}
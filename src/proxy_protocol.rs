@@ -0,0 +1,278 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+use log::debug;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+use crate::{configuration::{ProxyProtocolConfig, ProxyProtocolVersion}, tunnel::TunnelCtx};
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+const V2_CMD_LOCAL: u8 = 0x20;
+const V2_CMD_PROXY: u8 = 0x21;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+// the length of the v2 signature, every v1 header is longer ("PROXY UNKNOWN\r\n" is 15 bytes)
+const MIN_HEADER_SIZE: usize = 12;
+const V1_MAX_HEADER_SIZE: usize = 107;
+
+// PP2_TYPE_UNIQUE_ID from the spec, carries the TunnelCtx id
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+// first type of the custom range, carries the authenticated user
+const PP2_TYPE_USER: u8 = 0xE0;
+
+/// Builds the PROXY protocol header describing the client side of the tunnel.
+/// When the addresses are unknown the header says so (`UNKNOWN` / `LOCAL`),
+/// so the upstream still gets a well-formed preamble.
+pub fn encode_header(config:&ProxyProtocolConfig, ctx:&TunnelCtx) -> BytesMut {
+    let addresses = ctx.client_addr().zip(ctx.local_addr());
+
+    match config.version {
+        ProxyProtocolVersion::V1 => {
+            if config.send_user || config.send_tunnel_id {
+                debug!("PROXY protocol v1 has no TLVs, ignoring them, CTX={}", ctx);
+            }
+            encode_v1(addresses)
+        }
+        ProxyProtocolVersion::V2 => encode_v2(addresses, &tlvs(config, ctx)),
+    }
+}
+
+fn tlvs(config:&ProxyProtocolConfig, ctx:&TunnelCtx) -> Vec<(u8,Vec<u8>)> {
+    let mut tlvs = vec![];
+    if config.send_tunnel_id {
+        tlvs.push((PP2_TYPE_UNIQUE_ID, ctx.id().to_be_bytes().to_vec()));
+    }
+    if config.send_user && let Some(user) = ctx.user() {
+        tlvs.push((PP2_TYPE_USER, user.as_bytes().to_vec()));
+    }
+    tlvs
+}
+
+fn encode_v1(addresses:Option<(SocketAddr,SocketAddr)>) -> BytesMut {
+    let line = match addresses {
+        Some((src,dst)) if src.is_ipv4() == dst.is_ipv4() => {
+            let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+        }
+        _ => "PROXY UNKNOWN\r\n".to_string(),
+    };
+    BytesMut::from(line.as_bytes())
+}
+
+fn encode_v2(addresses:Option<(SocketAddr,SocketAddr)>, tlvs:&[(u8,Vec<u8>)]) -> BytesMut {
+    let mut address_block = BytesMut::new();
+    let (command,family) = match addresses {
+        Some((src,dst)) => match (src.ip(),dst.ip()) {
+            (IpAddr::V4(src_ip),IpAddr::V4(dst_ip)) => {
+                address_block.put_slice(&src_ip.octets());
+                address_block.put_slice(&dst_ip.octets());
+                address_block.put_u16(src.port());
+                address_block.put_u16(dst.port());
+                (V2_CMD_PROXY,V2_FAMILY_TCP4)
+            }
+            (src_ip,dst_ip) => {
+                // mixed families are sent as IPv4-mapped IPv6 addresses
+                address_block.put_slice(&to_ipv6_octets(src_ip));
+                address_block.put_slice(&to_ipv6_octets(dst_ip));
+                address_block.put_u16(src.port());
+                address_block.put_u16(dst.port());
+                (V2_CMD_PROXY,V2_FAMILY_TCP6)
+            }
+        },
+        None => (V2_CMD_LOCAL,V2_FAMILY_UNSPEC),
+    };
+
+    for (tlv_type,value) in tlvs {
+        address_block.put_u8(*tlv_type);
+        address_block.put_u16(value.len() as u16);
+        address_block.put_slice(value);
+    }
+
+    let mut header = BytesMut::with_capacity(16 + address_block.len());
+    header.put_slice(V2_SIGNATURE);
+    header.put_u8(command);
+    header.put_u8(family);
+    header.put_u16(address_block.len() as u16);
+    header.put_slice(&address_block);
+    header
+}
+
+/// Reads the PROXY header a load balancer in front of us sends, v1 or v2.
+/// Returns the source address, `None` for `UNKNOWN` and `LOCAL` headers.
+/// Nothing past the header is consumed. A missing header is an error: the spec
+/// forbids guessing, the peer is either configured to send one or isn't trusted.
+pub async fn read_header<S: AsyncRead + Unpin>(stream:&mut S) -> io::Result<Option<SocketAddr>> {
+    let invalid = |reason:&str| {
+        debug!("Bad PROXY protocol header: {}", reason);
+        io::Error::from(io::ErrorKind::InvalidData)
+    };
+
+    let mut header = vec![0u8;MIN_HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+
+    if header == V2_SIGNATURE {
+        let mut fixed = [0u8;4];
+        stream.read_exact(&mut fixed).await?;
+        let mut block = vec![0u8;u16::from_be_bytes([fixed[2],fixed[3]]) as usize];
+        stream.read_exact(&mut block).await?;
+
+        return match (fixed[0],fixed[1]) {
+            (V2_CMD_LOCAL,_) => Ok(None),
+            (V2_CMD_PROXY,V2_FAMILY_TCP4) if block.len() >= 12 => Ok(Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(block[0],block[1],block[2],block[3])),
+                u16::from_be_bytes([block[8],block[9]]),
+            ))),
+            (V2_CMD_PROXY,V2_FAMILY_TCP6) if block.len() >= 36 => {
+                let octets: [u8;16] = block[..16].try_into().expect("Bug: length checked");
+                Ok(Some(SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::from(octets)).to_canonical(),
+                    u16::from_be_bytes([block[32],block[33]]),
+                )))
+            }
+            (V2_CMD_PROXY,_) => Ok(None),
+            _ => Err(invalid("unknown v2 command")),
+        };
+    }
+
+    if !header.starts_with(b"PROXY ") {
+        return Err(invalid("no header"));
+    }
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_HEADER_SIZE {
+            return Err(invalid("v1 header is too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip = src.parse::<IpAddr>().map_err(|_| invalid("bad source address"))?;
+            let port = src_port.parse::<u16>().map_err(|_| invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip,port)))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+fn to_ipv6_octets(ip:IpAddr) -> [u8;16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tunnel::TunnelCtxBuilder;
+
+    fn ctx(client:&str, local:&str) -> TunnelCtx {
+        TunnelCtxBuilder::default()
+            .id(42)
+            .client_addr(Some(client.parse().unwrap()))
+            .local_addr(Some(local.parse().unwrap()))
+            .user(Some("billy".to_string()))
+            .build()
+            .unwrap()
+    }
+
+    fn config(version:ProxyProtocolVersion) -> ProxyProtocolConfig {
+        ProxyProtocolConfig {
+            version,
+            send_user: true,
+            send_tunnel_id: true,
+        }
+    }
+
+    #[test]
+    fn test_v1_tcp4() {
+        let header = encode_header(
+            &config(ProxyProtocolVersion::V1),
+            &ctx("10.0.0.1:5555", "10.0.0.2:8080"),
+        );
+        assert_eq!(&header[..], b"PROXY TCP4 10.0.0.1 10.0.0.2 5555 8080\r\n");
+    }
+
+    #[test]
+    fn test_v1_unknown() {
+        let header = encode_header(&config(ProxyProtocolVersion::V1), &TunnelCtx::default());
+        assert_eq!(&header[..], b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn test_v2_tcp4_with_tlvs() {
+        let header = encode_header(
+            &config(ProxyProtocolVersion::V2),
+            &ctx("10.0.0.1:5555", "10.0.0.2:8080"),
+        );
+
+        assert_eq!(&header[..12], V2_SIGNATURE);
+        assert_eq!(header[12], V2_CMD_PROXY);
+        assert_eq!(header[13], V2_FAMILY_TCP4);
+        // 12 bytes of addresses, 3 + 16 of the id TLV, 3 + 5 of the user TLV
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]), 39);
+        assert_eq!(&header[16..20], &[10, 0, 0, 1]);
+        assert_eq!(&header[24..28], &[0x15, 0xb3, 0x1f, 0x90]);
+        assert_eq!(header[28], PP2_TYPE_UNIQUE_ID);
+        assert_eq!(&header[31..47], &42u128.to_be_bytes());
+        assert_eq!(header[47], PP2_TYPE_USER);
+        assert_eq!(&header[50..], b"billy");
+    }
+
+    #[test]
+    fn test_v2_mixed_families() {
+        let header = encode_header(
+            &config(ProxyProtocolVersion::V2),
+            &ctx("10.0.0.1:5555", "[::1]:8080"),
+        );
+        assert_eq!(header[13], V2_FAMILY_TCP6);
+        assert_eq!(&header[16..32], &"::ffff:10.0.0.1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for (client,local) in [("10.0.0.1:5555","10.0.0.2:8080"), ("[2001:db8::1]:5555","[::1]:8080")] {
+                let mut stream = encode_header(&config(version), &ctx(client, local)).to_vec();
+                stream.extend_from_slice(b"payload");
+                let mut stream = &stream[..];
+
+                assert_eq!(read_header(&mut stream).await.unwrap(), Some(client.parse().unwrap()));
+                assert_eq!(stream, b"payload");
+            }
+        }
+
+        let mut unknown = &b"PROXY UNKNOWN\r\nGET"[..];
+        assert_eq!(read_header(&mut unknown).await.unwrap(), None);
+        assert_eq!(unknown, b"GET");
+
+        let mut missing = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        assert!(read_header(&mut missing).await.is_err());
+    }
+
+    #[test]
+    fn test_v2_local() {
+        let header = encode_header(
+            &ProxyProtocolConfig {
+                version: ProxyProtocolVersion::V2,
+                send_user: false,
+                send_tunnel_id: false,
+            },
+            &TunnelCtx::default(),
+        );
+        assert_eq!(header.len(), 16);
+        assert_eq!(header[12], V2_CMD_LOCAL);
+        assert_eq!(header[13], V2_FAMILY_UNSPEC);
+    }
+}
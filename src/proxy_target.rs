@@ -1,33 +1,93 @@
-use std::{collections::HashMap, hash::Hash, marker::PhantomData, net::SocketAddr, sync::{Arc, RwLock}, time::Instant};
-use derive_builder;
+use std::{collections::HashMap, marker::PhantomData, net::SocketAddr, pin::Pin, sync::{Arc, RwLock}, task::{Context, Poll}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use derive_builder::Builder;
+use log::{debug, error, info};
+use rand::Rng;
+use serde::Serialize;
 
-use humantime_serde::re::humantime::Duration;
-use tokio::io;
+use tokio::{io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf}, net::TcpStream, task::JoinHandle, time::{sleep, timeout, timeout_at}};
 
-use rand::thread_rng;
+use crate::{
+    circuit_breaker::{self, CircuitBreakers},
+    configuration::{IpFamilyPolicy, TargetConnectionConfig},
+    connect_udp::UdpCapsuleStream,
+    dns_backend::DnsBackend,
+    happy_eyeballs,
+    outbound,
+    parent_proxy,
+    proxy_protocol,
+    tls_client::ClientStream,
+    tunnel::{TargetConnector, TargetProtocol, TunnelCtx, TunnelTarget},
+    upstream_tls,
+};
 
-use crate::tunnel::TunnelCtx;
+struct CachedSocketAddr {
+    // empty for a failed lookup (negative entry)
+    addrs: Vec<SocketAddr>,
+    // milliseconds since the resolver's `start_time`
+    expires_at: u128,
+    last_used: u128,
+    // round-robin position of the first address handed out
+    next: usize,
+}
 
-type CachedSocketAddr = (Vec<SocketAddr>,u128);
+impl CachedSocketAddr {
+    fn next_round_robin(&mut self) -> Vec<SocketAddr> {
+        let mut addrs = self.addrs.clone();
+        let first = self.next % addrs.len();
+        addrs.rotate_left(first);
+        self.next = self.next.wrapping_add(1);
+        addrs
+    }
+}
 
 #[async_trait]
 pub trait DnsResolver {
-    async fn resolve(&mut self, target:&str) -> io::Result<SocketAddr>;
+    /// All the addresses of `target`, in the order they should be tried.
+    async fn resolve(&mut self, target:&str) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Time source of the DNS cache and the circuit breakers, so expiry can be tested without waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Clone,Copy,Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Snapshot of a cache entry, for operators.
+#[derive(Clone,Debug,Serialize)]
+pub struct DnsCacheEntry {
+    pub target: String,
+    // empty for a cached failure
+    pub addrs: Vec<SocketAddr>,
+    pub expires_in: Duration,
+    pub idle_for: Duration,
 }
 
 #[derive(Clone)]
 pub struct SimpleCachingDnsResolver {
     cache: Arc<RwLock<HashMap<String, CachedSocketAddr>>>,
+    backend: Arc<DnsBackend>,
     ttl:Duration,
+    negative_ttl:Duration,
+    max_entries:usize,
+    clock:Arc<dyn Clock>,
     start_time:Instant
-        
+
 }
 
 #[async_trait]
 impl DnsResolver for SimpleCachingDnsResolver {
-    async fn resolve(&mut self, target:&str) -> io::Result<SocketAddr> {
+    async fn resolve(&mut self, target:&str) -> io::Result<Vec<SocketAddr>> {
         match self.try_find(target).await {
-            Some(a) => Ok(a),
+            Some(a) => a,
             _ => Ok(self.resolve_and_cache(target).await?),
         }
     }
@@ -35,41 +95,601 @@ impl DnsResolver for SimpleCachingDnsResolver {
 }
 
 impl SimpleCachingDnsResolver {
-    pub fn new(ttl: Duration) -> Self {
+    pub fn new(backend: DnsBackend, ttl: Duration, negative_ttl: Duration, max_entries: usize) -> Self {
+        Self::with_clock(backend, ttl, negative_ttl, max_entries, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(backend: DnsBackend, ttl: Duration, negative_ttl: Duration, max_entries: usize, clock: Arc<dyn Clock>) -> Self {
         Self {
             cache: Arc::new(RwLock::new(HashMap::new())),
+            backend: Arc::new(backend),
             ttl,
-            start_time: Instant::now(),
+            negative_ttl,
+            max_entries: max_entries.max(1),
+            start_time: clock.now(),
+            clock,
+        }
+    }
+
+    /// Re-resolves, every half `window`, the entries that expire within `window`
+    /// and were used during the last TTL, so hot targets never miss the cache.
+    pub fn spawn_refresh_ahead(&self, window:Duration) -> JoinHandle<()> {
+        let resolver = self.clone();
+        let period = (window / 2).max(Duration::from_millis(100));
+        tokio::spawn(async move {
+            loop {
+                sleep(period).await;
+                resolver.refresh_expiring(window).await;
+            }
+        })
+    }
+
+    /// Returns the number of refreshed entries. A failed refresh leaves the entry
+    /// to expire as usual, a transient error doesn't replace good addresses.
+    pub async fn refresh_expiring(&self, window:Duration) -> usize {
+        let now = self.now_millis();
+        let targets: Vec<String> = {
+            let cache = self.cache.read().expect("DNS cache lock is poisoned");
+            cache
+                .iter()
+                .filter(|(_, entry)| {
+                    !entry.addrs.is_empty()
+                        && entry.expires_at > now
+                        && entry.expires_at <= now + window.as_millis()
+                        && entry.last_used + self.ttl.as_millis() >= now
+                })
+                .map(|(target, _)| target.clone())
+                .collect()
+        };
+
+        let mut refreshed = 0;
+        for target in targets {
+            match self.lookup(&target).await {
+                Ok(addrs) => {
+                    let expires_at = self.now_millis() + self.ttl.as_millis();
+                    let mut cache = self.cache.write().expect("DNS cache lock is poisoned");
+                    // flushed while we were resolving, let the next request cache it again
+                    if let Some(entry) = cache.get_mut(&target) {
+                        entry.addrs = addrs;
+                        entry.expires_at = expires_at;
+                        refreshed += 1;
+                    }
+                }
+                Err(e) => debug!("Failed to refresh DNS {}: {}", target, e),
+            }
+        }
+        if refreshed > 0 {
+            debug!("Refreshed {} DNS cache entries ahead of expiry", refreshed);
+        }
+        refreshed
+    }
+
+    /// Live entries, sorted by target.
+    pub fn entries(&self) -> Vec<DnsCacheEntry> {
+        let now = self.now_millis();
+        let cache = self.cache.read().expect("DNS cache lock is poisoned");
+        let mut entries: Vec<DnsCacheEntry> = cache
+            .iter()
+            .filter(|(_, entry)| entry.expires_at > now)
+            .map(|(target, entry)| SimpleCachingDnsResolver::snapshot(target, entry, now))
+            .collect();
+        entries.sort_by(|a, b| a.target.cmp(&b.target));
+        entries
+    }
+
+    pub fn entry(&self, target:&str) -> Option<DnsCacheEntry> {
+        let now = self.now_millis();
+        let cache = self.cache.read().expect("DNS cache lock is poisoned");
+        cache
+            .get(target)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| SimpleCachingDnsResolver::snapshot(target, entry, now))
+    }
+
+    /// Drops `target`, the next request resolves it again.
+    pub fn flush(&self, target:&str) -> bool {
+        let removed = self.cache.write().expect("DNS cache lock is poisoned").remove(target).is_some();
+        if removed {
+            info!("Flushed DNS cache entry {}", target);
+        }
+        removed
+    }
+
+    /// Drops everything, returns the number of flushed entries.
+    pub fn flush_all(&self) -> usize {
+        let mut cache = self.cache.write().expect("DNS cache lock is poisoned");
+        let flushed = cache.len();
+        cache.clear();
+        info!("Flushed {} DNS cache entries", flushed);
+        flushed
+    }
+
+    fn snapshot(target:&str, entry:&CachedSocketAddr, now:u128) -> DnsCacheEntry {
+        DnsCacheEntry {
+            target: target.to_string(),
+            addrs: entry.addrs.clone(),
+            expires_in: Duration::from_millis((entry.expires_at - now) as u64),
+            idle_for: Duration::from_millis(now.saturating_sub(entry.last_used) as u64),
+        }
+    }
+
+    fn now_millis(&self) -> u128 {
+        self.clock.now().duration_since(self.start_time).as_millis()
+    }
+
+    /// `None` on a miss or an expired entry, a cached failure is `Some(Err(_))`.
+    async fn try_find(&mut self, target:&str) -> Option<io::Result<Vec<SocketAddr>>> {
+        let now = self.now_millis();
+        let mut cache = self.cache.write().expect("DNS cache lock is poisoned");
+
+        let entry = cache.get_mut(target)?;
+        if entry.expires_at <= now {
+            debug!("DNS cache entry for {} expired", target);
+            cache.remove(target);
+            return None;
+        }
+
+        entry.last_used = now;
+        if entry.addrs.is_empty() {
+            Some(Err(io::Error::from(io::ErrorKind::AddrNotAvailable)))
+        } else {
+            Some(Ok(entry.next_round_robin()))
+        }
+    }
+
+    async fn resolve_and_cache(&mut self, target:&str) -> io::Result<Vec<SocketAddr>> {
+        match self.lookup(target).await {
+            Ok(addrs) => {
+                self.store(target, addrs.clone(), self.ttl);
+                Ok(addrs)
+            }
+            Err(e) => {
+                self.store(target, vec![], self.negative_ttl);
+                Err(e)
+            }
+        }
+    }
+
+    async fn lookup(&self, target:&str) -> io::Result<Vec<SocketAddr>> {
+        debug!("Resolving DNS {}", target);
+        let resolved = self.backend.lookup(target).await?;
+        debug!("Resolved DNS {} to {:?}", target, resolved);
+
+        if resolved.is_empty() {
+            error!("Cannot resolve DNS {}", target);
+            return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
         }
+        Ok(resolved)
+    }
+
+    fn store(&self, target:&str, addrs:Vec<SocketAddr>, ttl:Duration) {
+        let now = self.now_millis();
+        let mut cache = self.cache.write().expect("DNS cache lock is poisoned");
+
+        if !cache.contains_key(target) && cache.len() >= self.max_entries {
+            cache.retain(|_, entry| entry.expires_at > now);
+        }
+        if !cache.contains_key(target) && cache.len() >= self.max_entries {
+            // a linear scan is fine here, it only happens on a miss with a full cache
+            let least_recently_used = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(host, _)| host.clone());
+            if let Some(host) = least_recently_used {
+                debug!("DNS cache is full, evicting {}", host);
+                cache.remove(&host);
+            }
+        }
+
+        cache.insert(target.to_string(), CachedSocketAddr {
+            addrs,
+            expires_at: now + ttl.as_millis(),
+            last_used: now,
+            // the caller already got the addresses in their original order
+            next: 1,
+        });
     }
 }
 
 #[derive(Clone,Builder)]
 pub struct SimpleTcpConnector<D, R:DnsResolver> {
-    connect_timeout: Duration,
+    target_config: TargetConnectionConfig,
     tunnel_ctx: TunnelCtx,
     dns_resolver:R,
+    circuit_breakers: CircuitBreakers,
     #[builder(setter(skip))]
-    _phantom_target:PhantomData<D>, 
+    _phantom_target:PhantomData<D>,
 }
 
 
+/// Client bytes read with the tunnel request, owed to the target before relaying.
 #[derive(Eq,PartialEq,Debug,Clone)]
 pub struct Nugget {
     data:Arc<Vec<u8>>
 }
 
+impl Nugget {
+    pub fn new<T: Into<Vec<u8>>>(data:T) -> Self {
+        Self {
+            data: Arc::new(data.into()),
+        }
+    }
+
+    pub fn data(&self) -> Arc<Vec<u8>> {
+        self.data.clone()
+    }
+}
+
 impl<D,R> SimpleTcpConnector<D,R>
-where 
+where
     R:DnsResolver,
 {
-    pub fn new(dns_resolver:R,connection_timeout:Duration,tunnel_ctx_:TunnelCtx) -> Self  {
+    pub fn new(dns_resolver:R,target_config:TargetConnectionConfig,tunnel_ctx:TunnelCtx,circuit_breakers:CircuitBreakers) -> Self  {
         Self {
             dns_resolver,
-            connect_timeout,
+            target_config,
             tunnel_ctx,
+            circuit_breakers,
             _phantom_target:PhantomData,
         }
 
     }
 }
+
+#[async_trait]
+impl<D,R> TargetConnector for SimpleTcpConnector<D,R>
+where
+    D: TunnelTarget<Addr = String> + Send + Sync + Sized,
+    R: DnsResolver + Send + Sync + 'static,
+{
+    type Target = D;
+    type Stream = TargetStream;
+
+    async fn connect(&mut self, target:&Self::Target) -> io::Result<Self::Stream> {
+        // routes and the PROXY header follow what the client asked for
+        let requested_addr = target.target_addr();
+        let target_addr = match self.target_config.rewrite_target(&requested_addr) {
+            Some(rewritten) => {
+                info!("Rewrote target {} to {}, CTX={}", requested_addr, rewritten, self.tunnel_ctx);
+                rewritten
+            }
+            None => requested_addr.clone(),
+        };
+        let connect_timeout = self.target_config.connection_timeout;
+        if target.target_protocol() == TargetProtocol::Udp && !self.target_config.parent_proxies_for(&requested_addr).is_empty() {
+            error!("UDP target {} can't be reached through parent proxies, CTX={}", target_addr, self.tunnel_ctx);
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        if target.target_protocol() == TargetProtocol::Udp && self.target_config.upstream_tls_for(&requested_addr).is_some() {
+            error!("TLS can't be originated towards UDP target {}, CTX={}", target_addr, self.tunnel_ctx);
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+
+        // retries share the connection timeout, a backoff that would overrun it isn't slept
+        let deadline = Instant::now() + connect_timeout;
+        let mut retry = 0;
+        let mut stream = loop {
+            let permit = self.circuit_breakers.acquire(&target_addr, &self.tunnel_ctx)?;
+            let result = self.establish(&requested_addr, &target_addr, target.target_protocol(), deadline).await;
+            permit.record(result.as_ref().map(|_| ()).map_err(io::Error::kind));
+
+            match result {
+                Ok(stream) => break stream,
+                Err(e) if retry < self.target_config.connect_retries && circuit_breaker::is_connection_failure(e.kind()) => {
+                    let backoff = jittered_backoff(self.target_config.retry_backoff, retry);
+                    if Instant::now() + backoff >= deadline {
+                        return Err(e);
+                    }
+                    retry += 1;
+                    info!("Retrying {} in {:?} after {}, retry {}, CTX={}", target_addr, backoff, e, retry, self.tunnel_ctx);
+                    sleep(backoff).await;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if let TargetStream::Udp(_) = stream {
+            return Ok(stream);
+        }
+
+        // the PROXY header has to be the very first bytes the target sees
+        if let Some(proxy_protocol) = self.target_config.proxy_protocol_for(&requested_addr) {
+            let header = proxy_protocol::encode_header(proxy_protocol, &self.tunnel_ctx);
+            debug!("Sending PROXY protocol {:?} header to {}, CTX={}", proxy_protocol.version, target_addr, self.tunnel_ctx);
+
+            match timeout(connect_timeout, stream.write_all(&header)).await {
+                Ok(written) => written?,
+                Err(_) => {
+                    error!("Timeout sending PROXY header to {}, CTX={}", target_addr, self.tunnel_ctx);
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+            }
+        }
+
+        match self.target_config.upstream_tls_for(&requested_addr) {
+            // a second session around the client's would garble its handshake
+            Some(_) if target.target_protocol() == TargetProtocol::Tls => {
+                debug!("Not originating TLS to {}, the client's TLS is passed through, CTX={}", target_addr, self.tunnel_ctx);
+                Ok(stream)
+            }
+            Some(tls) => upstream_tls::wrap(stream, tls, &target_addr, &self.tunnel_ctx).await,
+            None => Ok(stream),
+        }
+    }
+}
+
+impl<D,R> SimpleTcpConnector<D,R>
+where
+    R: DnsResolver,
+{
+    async fn establish(
+        &mut self,
+        requested_addr:&str,
+        target_addr:&str,
+        protocol:TargetProtocol,
+        deadline:Instant,
+    ) -> io::Result<TargetStream> {
+        let ip_family = self.target_config.ip_family_for(requested_addr);
+        let parent_proxies = self.target_config.parent_proxies_for(requested_addr);
+        if parent_proxies.is_empty() {
+            return self.connect_direct(requested_addr, target_addr, protocol, ip_family, deadline).await;
+        }
+        parent_proxy::connect(
+            parent_proxies,
+            target_addr,
+            &self.target_config,
+            ip_family,
+            &mut self.dns_resolver,
+            &self.tunnel_ctx,
+        ).await
+    }
+
+    async fn connect_direct(
+        &mut self,
+        requested_addr:&str,
+        target_addr:&str,
+        protocol:TargetProtocol,
+        ip_family:IpFamilyPolicy,
+        deadline:Instant,
+    ) -> io::Result<TargetStream> {
+        let addrs = resolve_target(&self.target_config, &mut self.dns_resolver, target_addr).await?;
+        let addrs = ip_family.apply(addrs);
+        if addrs.is_empty() {
+            error!("No addresses of {} allowed by {:?}, CTX={}", target_addr, ip_family, self.tunnel_ctx);
+            return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+        }
+
+        let source = self.target_config.source_for(requested_addr, self.tunnel_ctx.user());
+        let options = self.target_config.socket_options_for(requested_addr);
+        if protocol == TargetProtocol::Udp {
+            let Some(addr) = addrs.iter().find(|addr| source.is_none_or(|source| source.allows(addr))).copied() else {
+                error!("No source address for the families of {}, CTX={}", target_addr, self.tunnel_ctx);
+                return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+            };
+            let socket = outbound::connect_udp(addr, source, options).await?;
+            debug!("UDP socket {:?} connected to {} ({}), CTX={}", socket.local_addr(), target_addr, addr, self.tunnel_ctx);
+            return Ok(TargetStream::Udp(UdpCapsuleStream::new(socket)));
+        }
+
+        let connection = happy_eyeballs::connect(&addrs, ip_family, self.target_config.connection_attempt_delay, source, options);
+        let (stream,addr) = match timeout_at(deadline.into(), connection).await {
+            Ok(connected) => connected?,
+            Err(_) => {
+                error!("Timeout connecting to {} ({:?}), CTX={}", target_addr, addrs, self.tunnel_ctx);
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+        };
+        info!("Connected to {} (requested {}) via {}, CTX={}", target_addr, requested_addr, addr, self.tunnel_ctx);
+        Ok(TargetStream::Tcp(stream))
+    }
+}
+
+/// Exponential backoff with full jitter: anywhere up to `base * 2^retry`.
+fn jittered_backoff(base:Duration, retry:u32) -> Duration {
+    let ceiling = base.saturating_mul(1 << retry.min(16));
+    ceiling.mul_f64(rand::rng().random::<f64>())
+}
+
+/// Addresses of `target`, the configured `hosts` take precedence over the resolver.
+pub async fn resolve_target<R: DnsResolver>(
+    target_config:&TargetConnectionConfig,
+    dns_resolver:&mut R,
+    target:&str,
+) -> io::Result<Vec<SocketAddr>> {
+    match target_config.static_addrs(target) {
+        Some(addrs) => {
+            debug!("Using static addresses {:?} for {}", addrs, target);
+            Ok(addrs)
+        }
+        None => dns_resolver.resolve(target).await,
+    }
+}
+
+/// Connection to the target, whatever transport the tunnel ended up using.
+pub enum TargetStream {
+    Tcp(TcpStream),
+    Udp(UdpCapsuleStream),
+    // through a parent proxy over TLS
+    Tls(Box<ClientStream<TcpStream>>),
+    // TLS we originated, over any of the above
+    UpstreamTls(Box<ClientStream<TargetStream>>),
+}
+
+impl AsyncRead for TargetStream {
+    fn poll_read(self:Pin<&mut Self>, cx:&mut Context<'_>, buf:&mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            TargetStream::Udp(stream) => Pin::new(stream).poll_read(cx, buf),
+            TargetStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            TargetStream::UpstreamTls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TargetStream {
+    fn poll_write(self:Pin<&mut Self>, cx:&mut Context<'_>, buf:&[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            TargetStream::Udp(stream) => Pin::new(stream).poll_write(cx, buf),
+            TargetStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            TargetStream::UpstreamTls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self:Pin<&mut Self>, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            TargetStream::Udp(stream) => Pin::new(stream).poll_flush(cx),
+            TargetStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            TargetStream::UpstreamTls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self:Pin<&mut Self>, cx:&mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            TargetStream::Udp(stream) => Pin::new(stream).poll_shutdown(cx),
+            TargetStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            TargetStream::UpstreamTls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::DnsBackendConfig;
+    use std::sync::Mutex;
+
+    struct MockClock {
+        now: Mutex<Instant>,
+    }
+
+    impl MockClock {
+        fn advance(&self, duration:Duration) {
+            let mut now = self.now.lock().unwrap();
+            *now += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn resolver(max_entries:usize) -> (SimpleCachingDnsResolver,Arc<MockClock>) {
+        let clock = Arc::new(MockClock { now: Mutex::new(Instant::now()) });
+        let resolver = SimpleCachingDnsResolver::with_clock(
+            DnsBackend::new(DnsBackendConfig::System, Duration::from_secs(1)).unwrap(),
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            max_entries,
+            clock.clone(),
+        );
+        (resolver,clock)
+    }
+
+    fn expires_at(resolver:&SimpleCachingDnsResolver, target:&str) -> Option<u128> {
+        resolver.cache.read().unwrap().get(target).map(|entry| entry.expires_at)
+    }
+
+    #[tokio::test]
+    async fn test_entries_expire_on_ttl() {
+        let (mut resolver,clock) = resolver(10);
+
+        resolver.resolve("127.0.0.1:80").await.unwrap();
+        assert_eq!(expires_at(&resolver, "127.0.0.1:80"), Some(60_000));
+
+        clock.advance(Duration::from_secs(59));
+        assert!(resolver.try_find("127.0.0.1:80").await.is_some());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(resolver.try_find("127.0.0.1:80").await.is_none());
+        resolver.resolve("127.0.0.1:80").await.unwrap();
+        assert_eq!(expires_at(&resolver, "127.0.0.1:80"), Some(120_000));
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_cached_briefly() {
+        let (mut resolver,clock) = resolver(10);
+
+        // an invalid port fails without touching the network
+        assert!(resolver.resolve("localhost:http").await.is_err());
+        assert!(matches!(resolver.try_find("localhost:http").await, Some(Err(_))));
+
+        clock.advance(Duration::from_secs(5));
+        assert!(resolver.try_find("localhost:http").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_is_evicted() {
+        let (mut resolver,clock) = resolver(2);
+
+        resolver.resolve("127.0.0.1:1").await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        resolver.resolve("127.0.0.1:2").await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        resolver.resolve("127.0.0.1:1").await.unwrap();
+        resolver.resolve("127.0.0.1:3").await.unwrap();
+
+        assert!(expires_at(&resolver, "127.0.0.1:1").is_some());
+        assert!(expires_at(&resolver, "127.0.0.1:2").is_none());
+        assert!(expires_at(&resolver, "127.0.0.1:3").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_ahead_keeps_hot_entries() {
+        let (mut resolver,clock) = resolver(10);
+
+        resolver.resolve("127.0.0.1:1").await.unwrap();
+        resolver.resolve("127.0.0.1:2").await.unwrap();
+        clock.advance(Duration::from_secs(55));
+        resolver.resolve("127.0.0.1:1").await.unwrap();
+
+        // too far from expiry
+        assert_eq!(resolver.refresh_expiring(Duration::from_secs(1)).await, 0);
+        assert_eq!(resolver.refresh_expiring(Duration::from_secs(10)).await, 2);
+        assert_eq!(expires_at(&resolver, "127.0.0.1:1"), Some(115_000));
+
+        // 127.0.0.1:2 wasn't used for a whole TTL, it's left to expire
+        clock.advance(Duration::from_secs(55));
+        assert_eq!(resolver.refresh_expiring(Duration::from_secs(10)).await, 1);
+        assert_eq!(expires_at(&resolver, "127.0.0.1:1"), Some(170_000));
+        assert_eq!(expires_at(&resolver, "127.0.0.1:2"), Some(115_000));
+    }
+
+    #[tokio::test]
+    async fn test_inspect_and_flush() {
+        let (mut resolver,clock) = resolver(10);
+
+        resolver.resolve("127.0.0.1:1").await.unwrap();
+        assert!(resolver.resolve("localhost:http").await.is_err());
+        clock.advance(Duration::from_secs(2));
+
+        let entries = resolver.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].target, "127.0.0.1:1");
+        assert_eq!(entries[0].addrs, vec!["127.0.0.1:1".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(entries[0].expires_in, Duration::from_secs(58));
+        assert_eq!(entries[0].idle_for, Duration::from_secs(2));
+        assert!(entries[1].addrs.is_empty());
+
+        assert!(resolver.flush("localhost:http"));
+        assert!(!resolver.flush("localhost:http"));
+        assert!(resolver.entry("localhost:http").is_none());
+        assert!(resolver.entry("127.0.0.1:1").is_some());
+
+        assert_eq!(resolver.flush_all(), 1);
+        assert!(resolver.entries().is_empty());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let addrs: Vec<SocketAddr> = vec!["10.0.0.1:80".parse().unwrap(), "10.0.0.2:80".parse().unwrap()];
+        let mut entry = CachedSocketAddr { addrs: addrs.clone(), expires_at: 0, last_used: 0, next: 1 };
+
+        assert_eq!(entry.next_round_robin()[0], addrs[1]);
+        assert_eq!(entry.next_round_robin()[0], addrs[0]);
+        assert_eq!(entry.next_round_robin().len(), 2);
+    }
+}
@@ -1,11 +1,14 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use derive_builder::Builder;
+use log::{debug, error, info};
+use tokio::{io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, time::timeout};
 
 
 pub const NO_TIMEOUT: Duration = Duration::from_secs(300);
 pub const NO_BANDWITH_LIMIT: u64 = 1_000_000_000_000_u64;
+const BUFFER_SIZE: usize = 16 * 1024;
 
 #[derive(Builder,Deserialize,Clone)]
 pub struct RelayPolicy {
@@ -14,3 +17,111 @@ pub struct RelayPolicy {
     pub min_rate_bpm:u64,
     pub max_rate_bps:u64,
 }
+
+#[derive(Builder,Clone)]
+pub struct Relay {
+    name: &'static str,
+    relay_policy: RelayPolicy,
+    // the tunnel context as logged, this module is also part of the configuration library
+    tunnel_ctx: String,
+}
+
+#[derive(Builder,Clone,Debug,Serialize)]
+pub struct RelayStats {
+    pub shutdown_reason: RelayShutdownReason,
+    pub total_bytes: usize,
+    pub event_count: usize,
+    pub duration: Duration,
+}
+
+#[derive(Clone,Debug,Eq,PartialEq,Serialize)]
+pub enum RelayShutdownReason {
+    GracefulShutdown,
+    ReadError,
+    WriteError,
+    ReaderTimeout,
+    WriterTimeout,
+    TooSlow,
+    TooFast,
+}
+
+impl Relay {
+    pub async fn relay_data<R,W>(self, mut source:R, mut dest:W) -> io::Result<RelayStats>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buffer = [0u8;BUFFER_SIZE];
+        let mut total_bytes = 0;
+        let mut event_count = 0;
+        let start_time = Instant::now();
+
+        let shutdown_reason = loop {
+            let read = match timeout(self.relay_policy.idle_timeout, source.read(&mut buffer)).await {
+                Err(_) => break RelayShutdownReason::ReaderTimeout,
+                Ok(Err(e)) => {
+                    error!("{} failed to read: {}, CTX={}", self.name, e, self.tunnel_ctx);
+                    break RelayShutdownReason::ReadError;
+                }
+                Ok(Ok(0)) => break RelayShutdownReason::GracefulShutdown,
+                Ok(Ok(n)) => n,
+            };
+
+            let write = async {
+                dest.write_all(&buffer[..read]).await?;
+                dest.flush().await
+            };
+            match timeout(self.relay_policy.idle_timeout, write).await {
+                Err(_) => break RelayShutdownReason::WriterTimeout,
+                Ok(Err(e)) => {
+                    error!("{} failed to write {} bytes: {}, CTX={}", self.name, read, e, self.tunnel_ctx);
+                    break RelayShutdownReason::WriteError;
+                }
+                Ok(Ok(_)) => {}
+            }
+
+            total_bytes += read;
+            event_count += 1;
+
+            if let Err(rate_violation) = self.relay_policy.check_transmission_rates(&start_time,total_bytes) {
+                break rate_violation;
+            }
+        };
+
+        dest.shutdown().await.unwrap_or_default();
+
+        let duration = start_time.elapsed();
+        info!(
+            "{} closed: {:?}, {} bytes, {} events, {:?}, CTX={}",
+            self.name, shutdown_reason, total_bytes, event_count, duration, self.tunnel_ctx
+        );
+
+        Ok(RelayStats {
+            shutdown_reason,
+            total_bytes,
+            event_count,
+            duration,
+        })
+    }
+}
+
+impl RelayPolicy {
+    pub fn check_transmission_rates(&self, start:&Instant, total_bytes:usize) -> Result<(),RelayShutdownReason> {
+        // rates are meaningless for the first second, the connection is still warming up
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed < 1.0 {
+            return Ok(());
+        }
+
+        let bytes_per_second = total_bytes as f64 / elapsed;
+        if bytes_per_second > self.max_rate_bps as f64 {
+            debug!("Relay is too fast: {} bps", bytes_per_second);
+            Err(RelayShutdownReason::TooFast)
+        } else if bytes_per_second * 60.0 < self.min_rate_bpm as f64 {
+            debug!("Relay is too slow: {} bpm", bytes_per_second * 60.0);
+            Err(RelayShutdownReason::TooSlow)
+        } else {
+            Ok(())
+        }
+    }
+}
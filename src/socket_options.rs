@@ -0,0 +1,173 @@
+use std::os::fd::AsFd;
+
+use log::error;
+use socket2::SockRef;
+#[cfg(target_os = "linux")]
+use socket2::TcpKeepalive;
+use tokio::io;
+
+use crate::configuration::{KeepaliveConfig, SocketOptions};
+
+// pending Fast Open requests a listener keeps
+#[cfg(target_os = "linux")]
+const FAST_OPEN_QUEUE_LENGTH: libc::c_int = 256;
+
+/// Tunes a TCP socket, before connecting or once accepted.
+pub fn apply_tcp<S: AsFd>(socket:&S, options:&SocketOptions, ipv4:bool) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    socket.set_tcp_nodelay(options.nodelay)?;
+    if let Some(keepalive) = &options.keepalive {
+        set_keepalive(&socket, keepalive)?;
+    }
+    if let Some(algorithm) = &options.congestion_control {
+        set_congestion_control(&socket, algorithm)?;
+    }
+    apply_ip(&socket, options, ipv4)
+}
+
+pub fn apply_udp<S: AsFd>(socket:&S, options:&SocketOptions, ipv4:bool) -> io::Result<()> {
+    apply_ip(&SockRef::from(socket), options, ipv4)
+}
+
+/// Sends the first data in the SYN, before `connect`.
+pub fn enable_fast_open_connect<S: AsFd>(socket:&S) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return set_tcp_option(socket, libc::TCP_FASTOPEN_CONNECT, 1, "TCP_FASTOPEN_CONNECT");
+    #[cfg(not(target_os = "linux"))]
+    return unsupported(socket, "TCP Fast Open");
+}
+
+/// Accepts data in the SYN, before `listen`.
+pub fn enable_fast_open_listen<S: AsFd>(socket:&S) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return set_tcp_option(socket, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE_LENGTH, "TCP_FASTOPEN");
+    #[cfg(not(target_os = "linux"))]
+    return unsupported(socket, "TCP Fast Open");
+}
+
+fn apply_ip(socket:&SockRef, options:&SocketOptions, ipv4:bool) -> io::Result<()> {
+    if let Some(mark) = options.mark {
+        set_mark(socket, mark)?;
+    }
+    if let Some(dscp) = options.dscp {
+        if dscp > 63 {
+            error!("DSCP {} is out of range, expected 0-63", dscp);
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        // the two low bits are ECN
+        let tos = u32::from(dscp) << 2;
+        let result = if ipv4 { socket.set_tos_v4(tos) } else { socket.set_tclass_v6(tos) };
+        result.map_err(|e| {
+            error!("Failed to set DSCP {}: {}", dscp, e);
+            e
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_keepalive(socket:&SockRef, keepalive:&KeepaliveConfig) -> io::Result<()> {
+    let mut params = TcpKeepalive::new().with_time(keepalive.time);
+    if let Some(interval) = keepalive.interval {
+        params = params.with_interval(interval);
+    }
+    if let Some(retries) = keepalive.retries {
+        params = params.with_retries(retries);
+    }
+    socket.set_tcp_keepalive(&params).map_err(|e| {
+        error!("Failed to set TCP keepalive: {}", e);
+        e
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive(socket:&SockRef, _keepalive:&KeepaliveConfig) -> io::Result<()> {
+    unsupported(socket, "TCP keepalive tuning")
+}
+
+#[cfg(target_os = "linux")]
+fn set_congestion_control(socket:&SockRef, algorithm:&str) -> io::Result<()> {
+    socket.set_tcp_congestion(algorithm.as_bytes()).map_err(|e| {
+        error!("Failed to set congestion control {}: {}", algorithm, e);
+        e
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_congestion_control(socket:&SockRef, _algorithm:&str) -> io::Result<()> {
+    unsupported(socket, "TCP_CONGESTION")
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket:&SockRef, mark:u32) -> io::Result<()> {
+    socket.set_mark(mark).map_err(|e| {
+        error!("Failed to set SO_MARK {}: {}", mark, e);
+        e
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(socket:&SockRef, _mark:u32) -> io::Result<()> {
+    unsupported(socket, "SO_MARK")
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_option<S: AsFd>(socket:&S, option:libc::c_int, value:libc::c_int, name:&str) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: a valid descriptor and a c_int sized value
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_fd().as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        error!("Failed to set {}: {}", name, e);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported<S>(_socket:&S, option:&str) -> io::Result<()> {
+    error!("{} is not supported on this platform", option);
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::{TcpListener, TcpSocket};
+
+    #[tokio::test]
+    async fn test_apply_tcp() {
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(KeepaliveConfig {
+                time: std::time::Duration::from_secs(60),
+                interval: Some(std::time::Duration::from_secs(10)),
+                retries: Some(4),
+            }),
+            dscp: Some(46),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpSocket::new_v4().unwrap();
+        apply_tcp(&socket, &options, true).unwrap();
+        enable_fast_open_connect(&socket).unwrap();
+
+        let stream = socket.connect(listener.local_addr().unwrap()).await.unwrap();
+        let tuned = SockRef::from(&stream);
+        assert!(tuned.tcp_nodelay().unwrap());
+        assert!(tuned.keepalive().unwrap());
+        assert_eq!(tuned.tos_v4().unwrap(), 46 << 2);
+
+        let out_of_range = SocketOptions { dscp: Some(64), ..Default::default() };
+        assert!(apply_tcp(&stream, &out_of_range, true).is_err());
+    }
+}
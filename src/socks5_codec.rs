@@ -0,0 +1,495 @@
+use std::{collections::HashMap, fmt, net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6}};
+
+use bytes::{Buf, BufMut, BytesMut};
+use derive_builder::Builder;
+use log::debug;
+use regex::Regex;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{proxy_target::Nugget, tunnel::{EstablishTunnelResult, TunnelCtx, TunnelTarget}};
+
+const SOCKS_VERSION: u8 = 0x05;
+const USER_PASS_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Performs the method negotiation and, when `users` are configured,
+/// the RFC 1929 username/password sub-negotiation.
+/// Returns the authenticated user name, if any.
+pub async fn socks5_auth_handshake<S>(
+    stream:&mut S,
+    users:&HashMap<String,String>,
+    ctx:&TunnelCtx,
+) -> io::Result<Option<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != SOCKS_VERSION {
+        debug!("Unsupported SOCKS version {}, CTX={}", version, ctx);
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let mut methods = vec![0u8;stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if users.is_empty() {
+        METHOD_NO_AUTH
+    } else {
+        METHOD_USER_PASS
+    };
+
+    if !methods.contains(&method) {
+        debug!("SOCKS client doesn't offer method {}, CTX={}", method, ctx);
+        stream.write_all(&[SOCKS_VERSION,METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+    }
+    stream.write_all(&[SOCKS_VERSION,method]).await?;
+
+    if method == METHOD_NO_AUTH {
+        return Ok(None);
+    }
+
+    if stream.read_u8().await? != USER_PASS_VERSION {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    let mut user = vec![0u8;stream.read_u8().await? as usize];
+    stream.read_exact(&mut user).await?;
+    let mut password = vec![0u8;stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+
+    let user = String::from_utf8_lossy(&user).to_string();
+    let authenticated = users
+        .get(&user)
+        .map(|expected| constant_time_eq(expected.as_bytes(), &password))
+        .unwrap_or(false);
+
+    if authenticated {
+        stream.write_all(&[USER_PASS_VERSION,0x00]).await?;
+        Ok(Some(user))
+    } else {
+        debug!("SOCKS authentication failed for user {}, CTX={}", user, ctx);
+        stream.write_all(&[USER_PASS_VERSION,0x01]).await?;
+        Err(io::Error::from(io::ErrorKind::PermissionDenied))
+    }
+}
+
+// compares every byte whatever the first difference, so the time taken doesn't
+// tell how much of a guessed password was right
+fn constant_time_eq(a:&[u8], b:&[u8]) -> bool {
+    let length_differs = (a.len() != b.len()) as u8;
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0))
+        .fold(length_differs, |difference,xor| difference | xor)
+        == 0
+}
+
+/// Client side of the handshake, for SOCKS5 parent proxies: negotiates the method,
+/// authenticates with `credentials` if the parent asks for it and sends a CONNECT.
+/// `resolved` is sent instead of the `host:port` target when DNS is resolved locally.
+/// A parent refusing the target by its rules is `PermissionDenied`.
+pub async fn socks5_connect<S>(
+    stream:&mut S,
+    credentials:Option<(&str,&str)>,
+    target:&str,
+    resolved:Option<SocketAddr>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = BytesMut::new();
+    match credentials {
+        Some(_) => request.put_slice(&[SOCKS_VERSION,2,METHOD_NO_AUTH,METHOD_USER_PASS]),
+        None => request.put_slice(&[SOCKS_VERSION,1,METHOD_NO_AUTH]),
+    }
+    stream.write_all(&request).await?;
+
+    let mut choice = [0u8;2];
+    stream.read_exact(&mut choice).await?;
+    match (choice,credentials) {
+        ([SOCKS_VERSION,METHOD_NO_AUTH],_) => {}
+        ([SOCKS_VERSION,METHOD_USER_PASS],Some((user,password))) => {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            let mut auth = BytesMut::new();
+            auth.put_u8(USER_PASS_VERSION);
+            auth.put_u8(user.len() as u8);
+            auth.put_slice(user.as_bytes());
+            auth.put_u8(password.len() as u8);
+            auth.put_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+
+            let mut status = [0u8;2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                debug!("SOCKS parent rejected user {}", user);
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+            }
+        }
+        _ => {
+            debug!("SOCKS parent chose unsupported method {:?}", choice);
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+        }
+    }
+
+    let mut request = BytesMut::new();
+    request.put_slice(&[SOCKS_VERSION,CMD_CONNECT,0x00]);
+    match resolved.or_else(|| target.parse().ok()) {
+        Some(addr) => encode_address(addr, &mut request),
+        None => {
+            let (host,port) = target
+                .rsplit_once(':')
+                .and_then(|(host,port)| Some((host,port.parse::<u16>().ok()?)))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+            if host.len() > 255 {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            request.put_u8(ATYP_DOMAIN);
+            request.put_u8(host.len() as u8);
+            request.put_slice(host.as_bytes());
+            request.put_u16(port);
+        }
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    // VER REP RSV, then BND.ADDR of whatever type the parent picked
+    let mut reply = vec![0u8;5];
+    stream.read_exact(&mut reply).await?;
+    let remaining = match reply[3] {
+        ATYP_IPV4 => 4 + 2 - 1,
+        ATYP_IPV6 => 16 + 2 - 1,
+        ATYP_DOMAIN => reply[4] as usize + 2,
+        _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+    };
+    let mut bound = vec![0u8;remaining];
+    stream.read_exact(&mut bound).await?;
+
+    match reply[1] {
+        REPLY_SUCCEEDED => Ok(()),
+        REPLY_NOT_ALLOWED => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        REPLY_TTL_EXPIRED => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        code => {
+            debug!("SOCKS parent failed to connect to {}, REP={}", target, code);
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        }
+    }
+}
+
+/// Decodes the SOCKS5 request that follows the authentication and encodes
+/// the reply, mapping `EstablishTunnelResult` to the RFC 1928 reply codes.
+#[derive(Clone,Builder)]
+pub struct Socks5Codec {
+    tunnel_ctx: TunnelCtx,
+    enabled_targets: Regex,
+}
+
+impl Decoder for Socks5Codec {
+    type Item = Socks5Target;
+    type Error = EstablishTunnelResult;
+
+    fn decode(&mut self, src:&mut BytesMut) -> Result<Option<Self::Item>,Self::Error> {
+        let (command,target,request_len) = match parse_request(src)? {
+            Some(request) => request,
+            None => return Ok(None),
+        };
+        src.advance(request_len);
+
+        let command = match command {
+            CMD_CONNECT => Socks5Command::Connect,
+            // the destinations are checked per datagram, `target` is only the client's hint
+            CMD_UDP_ASSOCIATE => return Ok(Some(Socks5Target {
+                target,
+                command: Socks5Command::UdpAssociate,
+            })),
+            _ => {
+                debug!("SOCKS command {} is not supported, CTX={}", command, self.tunnel_ctx);
+                return Err(EstablishTunnelResult::OperationNotAllowed);
+            }
+        };
+
+        if !self.enabled_targets.is_match(&target) {
+            debug!("Target {} is not allowed, only allowed is {}, CTX={}",
+                target,
+                self.enabled_targets,
+                self.tunnel_ctx
+            );
+            return Err(EstablishTunnelResult::Forbidden);
+        }
+
+        Ok(Some(Socks5Target { target, command }))
+    }
+}
+
+impl Encoder<EstablishTunnelResult> for Socks5Codec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item:EstablishTunnelResult, dst:&mut BytesMut) -> Result<(),Self::Error> {
+        // we don't expose the upstream socket, so BND.ADDR is always 0.0.0.0:0
+        encode_reply(&item, SocketAddr::from(([0,0,0,0],0)), dst);
+        Ok(())
+    }
+}
+
+/// Encodes `VER REP RSV ATYP BND.ADDR BND.PORT`.
+pub fn encode_reply(result:&EstablishTunnelResult, bound:SocketAddr, dst:&mut BytesMut) {
+    let reply = match result {
+        EstablishTunnelResult::Ok | EstablishTunnelResult::OkWithNugget => REPLY_SUCCEEDED,
+        EstablishTunnelResult::Forbidden | EstablishTunnelResult::TooManyRequest => REPLY_NOT_ALLOWED,
+        EstablishTunnelResult::OperationNotAllowed => REPLY_COMMAND_NOT_SUPPORTED,
+        EstablishTunnelResult::BadGateway | EstablishTunnelResult::ServiceUnavailable => REPLY_HOST_UNREACHABLE,
+        EstablishTunnelResult::GatewayTimeout => REPLY_TTL_EXPIRED,
+        EstablishTunnelResult::BadRequest
+        | EstablishTunnelResult::RequestTimeout
+        | EstablishTunnelResult::ServerError => REPLY_GENERAL_FAILURE,
+    };
+
+    dst.put_slice(&[SOCKS_VERSION,reply,0x00]);
+    encode_address(bound, dst);
+}
+
+/// Encodes `ATYP ADDR PORT`, shared by replies and UDP datagram headers.
+pub fn encode_address(addr:SocketAddr, dst:&mut BytesMut) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            dst.put_u8(ATYP_IPV4);
+            dst.put_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            dst.put_u8(ATYP_IPV6);
+            dst.put_slice(&addr.ip().octets());
+        }
+    }
+    dst.put_u16(addr.port());
+}
+
+/// Parses `VER CMD RSV ATYP DST.ADDR DST.PORT`.
+/// Returns the command, the `host:port` target and the request length,
+/// or `None` when the request is not complete yet.
+fn parse_request(src:&[u8]) -> Result<Option<(u8,String,usize)>,EstablishTunnelResult> {
+    if src.len() < 3 {
+        return Ok(None);
+    }
+    if src[0] != SOCKS_VERSION {
+        return Err(EstablishTunnelResult::BadRequest);
+    }
+
+    Ok(parse_address(&src[3..])?.map(|(target,address_len)| (src[1],target,3 + address_len)))
+}
+
+/// Parses `ATYP ADDR PORT` into a `host:port` target and its encoded length,
+/// or `None` when the address is not complete yet.
+pub fn parse_address(src:&[u8]) -> Result<Option<(String,usize)>,EstablishTunnelResult> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+
+    let address_len = match src[0] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => 1 + src[1] as usize,
+        atyp => {
+            debug!("Unsupported SOCKS address type {}", atyp);
+            return Err(EstablishTunnelResult::BadRequest);
+        }
+    };
+    let encoded_len = 1 + address_len + 2;
+    if src.len() < encoded_len {
+        return Ok(None);
+    }
+
+    let address = &src[1..1 + address_len];
+    let port = u16::from_be_bytes([src[encoded_len - 2], src[encoded_len - 1]]);
+
+    let target = match src[0] {
+        ATYP_IPV4 => {
+            let ip = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
+            SocketAddrV4::new(ip, port).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8;16];
+            octets.copy_from_slice(address);
+            SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0).to_string()
+        }
+        _ => {
+            let domain = std::str::from_utf8(&address[1..])
+                .map_err(|_| EstablishTunnelResult::BadRequest)?;
+            format!("{}:{}", domain, port)
+        }
+    };
+
+    Ok(Some((target,encoded_len)))
+}
+
+#[derive(Eq,PartialEq,Debug,Clone,Copy)]
+pub enum Socks5Command {
+    Connect,
+    UdpAssociate,
+}
+
+#[derive(Builder,Eq,PartialEq,Debug,Clone)]
+pub struct Socks5Target {
+    pub target: String,
+    pub command: Socks5Command,
+}
+
+impl TunnelTarget for Socks5Target {
+    type Addr = String;
+
+    fn target_addr(&self) -> Self::Addr {
+        self.target.clone()
+    }
+
+    // SOCKS clients only send data after the reply, so there is never a nugget
+    fn has_nugget(&self) -> bool {
+        false
+    }
+
+    fn target_nugget(&self) -> &Nugget {
+        unreachable!("Socks5Target never has a nugget")
+    }
+}
+
+impl fmt::Display for Socks5Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{}",self.target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn codec() -> Socks5Codec {
+        Socks5CodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(r"^(example\.com|10\.0\.0\.1|\[::1\]):443$").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake_against_server() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_util::codec::Framed;
+
+        let users = HashMap::from([("user".to_string(),"secret".to_string())]);
+        for (target,resolved,expected) in [
+            ("example.com:443", None, "example.com:443"),
+            ("example.com:443", Some("10.0.0.1:443".parse().unwrap()), "10.0.0.1:443"),
+        ] {
+            let (mut client,mut server) = tokio::io::duplex(512);
+            let users = users.clone();
+            let parent = tokio::spawn(async move {
+                let user = socks5_auth_handshake(&mut server, &users, &TunnelCtx::default()).await.unwrap();
+                let mut framed = Framed::new(server, codec());
+                let request = framed.next().await.unwrap().unwrap();
+                framed.send(EstablishTunnelResult::Ok).await.unwrap();
+                (user,request.target)
+            });
+
+            socks5_connect(&mut client, Some(("user","secret")), target, resolved).await.unwrap();
+            assert_eq!(parent.await.unwrap(), (Some("user".to_string()),expected.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake_refused() {
+        let (mut client,mut server) = tokio::io::duplex(512);
+        tokio::spawn(async move {
+            let mut greeting = [0u8;3];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[SOCKS_VERSION,METHOD_NO_AUTH]).await.unwrap();
+            let mut request = [0u8;10];
+            server.read_exact(&mut request).await.unwrap();
+            let mut reply = BytesMut::new();
+            encode_reply(&EstablishTunnelResult::Forbidden, SocketAddr::from(([0,0,0,0],0)), &mut reply);
+            server.write_all(&reply).await.unwrap();
+        });
+
+        let result = socks5_connect(&mut client, None, "10.0.0.1:443", None).await;
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret\0"));
+        assert!(!constant_time_eq(b"", b"\0"));
+    }
+
+    #[test]
+    fn test_decode_address_types() {
+        let mut buffer = BytesMut::from(&b"\x05\x01\x00\x03\x0bexample.com\x01\xbb"[..]);
+        assert_eq!(codec().decode(&mut buffer).unwrap().unwrap().target, "example.com:443");
+
+        let mut buffer = BytesMut::from(&b"\x05\x01\x00\x01\x0a\x00\x00\x01\x01\xbb"[..]);
+        assert_eq!(codec().decode(&mut buffer).unwrap().unwrap().target, "10.0.0.1:443");
+
+        let mut request = b"\x05\x01\x00\x04".to_vec();
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&[0x01,0xbb]);
+        let mut buffer = BytesMut::from(&request[..]);
+        assert_eq!(codec().decode(&mut buffer).unwrap().unwrap().target, "[::1]:443");
+    }
+
+    #[test]
+    fn test_decode_partial_and_rejected() {
+        let mut buffer = BytesMut::from(&b"\x05\x01\x00\x03\x0bexample"[..]);
+        assert_eq!(codec().decode(&mut buffer), Ok(None));
+
+        let mut buffer = BytesMut::from(&b"\x05\x01\x00\x03\x0bexample.org\x01\xbb"[..]);
+        assert_eq!(codec().decode(&mut buffer), Err(EstablishTunnelResult::Forbidden));
+
+        // BIND
+        let mut buffer = BytesMut::from(&b"\x05\x02\x00\x03\x0bexample.com\x01\xbb"[..]);
+        assert_eq!(codec().decode(&mut buffer), Err(EstablishTunnelResult::OperationNotAllowed));
+
+        // UDP ASSOCIATE is not subject to the ACL, the datagrams are
+        let mut buffer = BytesMut::from(&b"\x05\x03\x00\x01\x00\x00\x00\x00\x00\x00"[..]);
+        assert_eq!(codec().decode(&mut buffer).unwrap().unwrap().command, Socks5Command::UdpAssociate);
+    }
+
+    #[test]
+    fn test_encode_reply_codes() {
+        let mut buffer = BytesMut::new();
+        codec().encode(EstablishTunnelResult::Ok, &mut buffer).unwrap();
+        codec().encode(EstablishTunnelResult::BadGateway, &mut buffer).unwrap();
+        assert_eq!(buffer[1], REPLY_SUCCEEDED);
+        assert_eq!(buffer[11], REPLY_HOST_UNREACHABLE);
+    }
+
+    #[tokio::test]
+    async fn test_user_pass_handshake() {
+        let users = HashMap::from([("billy".to_string(),"secret".to_string())]);
+        let (mut client,mut server) = tokio::io::duplex(64);
+
+        client.write_all(b"\x05\x02\x00\x02\x01\x05billy\x06secret").await.unwrap();
+        let user = socks5_auth_handshake(&mut server, &users, &TunnelCtx::default()).await.unwrap();
+        assert_eq!(user.as_deref(), Some("billy"));
+
+        let mut response = [0u8;4];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [SOCKS_VERSION,METHOD_USER_PASS,USER_PASS_VERSION,0x00]);
+    }
+}
@@ -0,0 +1,503 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc, time::Instant};
+
+use bytes::BytesMut;
+use log::{debug, info};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt},
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinSet,
+    time::sleep_until,
+};
+
+use crate::{
+    configuration::TargetConnectionConfig,
+    outbound,
+    proxy_target::{resolve_target, DnsResolver},
+    relay::{RelayPolicy, RelayShutdownReason, RelayStats},
+    socks5_codec::{encode_address, parse_address},
+    tunnel::TunnelCtx,
+};
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+// each destination holds a socket, datagrams to further destinations are dropped
+const MAX_DESTINATIONS: usize = 256;
+const UPSTREAM_QUEUE_SIZE: usize = 64;
+
+// the socket of a destination and the address it's connected to, if it could be opened
+type Opened = Option<(Arc<UdpSocket>,SocketAddr)>;
+
+/// A single SOCKS5 UDP ASSOCIATE: a client facing socket the client sends
+/// encapsulated datagrams to, and an upstream socket per destination, bound and
+/// tuned like TCP connections to it. Upstream sockets are connected, so only the
+/// destinations the client sent to can answer it.
+/// A destination is resolved once, on its first datagram, so a flow keeps its
+/// address and source port however the DNS cache rotates its addresses.
+/// It lives as long as the controlling TCP connection and the idle timeout allow.
+pub struct UdpAssociation<R> {
+    tunnel_ctx: TunnelCtx,
+    client_socket: UdpSocket,
+    // by DST.ADDR:DST.PORT as the client sent it
+    destinations: HashMap<String,Destination>,
+    // resolving and opening destinations, off the relay loop
+    opening: JoinSet<(String,Opened)>,
+    // a receiver task per upstream socket, aborted with the association
+    upstream_receivers: JoinSet<()>,
+    upstream_sender: mpsc::Sender<(Vec<u8>,SocketAddr)>,
+    upstream_datagrams: mpsc::Receiver<(Vec<u8>,SocketAddr)>,
+    target_config: TargetConnectionConfig,
+    dns_resolver: R,
+    relay_policy: RelayPolicy,
+    // the client may only send from the address of its TCP connection
+    client_ip: Option<IpAddr>,
+    client_udp_addr: Option<SocketAddr>,
+}
+
+enum Destination {
+    // payloads waiting for the socket
+    Opening(Vec<Vec<u8>>),
+    Open(Arc<UdpSocket>),
+}
+
+struct UdpAccounting {
+    total_bytes: usize,
+    event_count: usize,
+}
+
+impl UdpAccounting {
+    fn sent(&mut self, bytes:usize) {
+        self.total_bytes += bytes;
+        self.event_count += 1;
+    }
+}
+
+impl<R: DnsResolver + Clone + Send + 'static> UdpAssociation<R> {
+    pub async fn bind(
+        tunnel_ctx:TunnelCtx,
+        target_config:TargetConnectionConfig,
+        dns_resolver:R,
+        relay_policy:RelayPolicy,
+    ) -> io::Result<Self> {
+        // bind next to the TCP listener, so the client can reach the relay the same way
+        let client_bind_ip = tunnel_ctx
+            .local_addr()
+            .map(|addr| addr.ip())
+            .unwrap_or(IpAddr::from([0,0,0,0]));
+        let client_socket = UdpSocket::bind(SocketAddr::new(client_bind_ip,0)).await?;
+        let (upstream_sender,upstream_datagrams) = mpsc::channel(UPSTREAM_QUEUE_SIZE);
+
+        Ok(Self {
+            client_ip: tunnel_ctx.client_addr().map(|addr| addr.ip()),
+            tunnel_ctx,
+            client_socket,
+            destinations: HashMap::new(),
+            opening: JoinSet::new(),
+            upstream_receivers: JoinSet::new(),
+            upstream_sender,
+            upstream_datagrams,
+            target_config,
+            dns_resolver,
+            relay_policy,
+            client_udp_addr: None,
+        })
+    }
+
+    /// The address to report as BND.ADDR in the reply.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.client_socket.local_addr()
+    }
+
+    /// Relays datagrams until the control connection is closed or the
+    /// association is idle for longer than the relay policy allows.
+    /// Returns the upstream and downstream stats.
+    pub async fn relay<C: AsyncRead + Unpin>(mut self, mut control:C) -> io::Result<(RelayStats,RelayStats)> {
+        let mut client_buffer = vec![0u8;MAX_DATAGRAM_SIZE];
+        let mut control_buffer = [0u8;64];
+
+        let mut upstream = UdpAccounting { total_bytes: 0, event_count: 0 };
+        let mut downstream = UdpAccounting { total_bytes: 0, event_count: 0 };
+        let start_time = Instant::now();
+        let mut last_activity = tokio::time::Instant::now();
+
+        let shutdown_reason = loop {
+            tokio::select! {
+                read = control.read(&mut control_buffer) => match read {
+                    // nothing is expected on the control connection, it's only watched for closing
+                    Ok(0) | Err(_) => break RelayShutdownReason::GracefulShutdown,
+                    Ok(_) => {}
+                },
+                received = self.client_socket.recv_from(&mut client_buffer) => {
+                    let (len,from) = received?;
+                    if let Some(sent) = self.forward_to_target(&client_buffer[..len], from).await {
+                        upstream.sent(sent);
+                        last_activity = tokio::time::Instant::now();
+                    }
+                },
+                Some(Ok((requested,opened))) = self.opening.join_next(), if !self.opening.is_empty() => {
+                    for sent in self.opened(requested, opened).await {
+                        upstream.sent(sent);
+                        last_activity = tokio::time::Instant::now();
+                    }
+                },
+                Some((payload,from)) = self.upstream_datagrams.recv() => {
+                    if let Some(sent) = self.forward_to_client(&payload, from).await {
+                        downstream.sent(sent);
+                        last_activity = tokio::time::Instant::now();
+                    }
+                },
+                _ = sleep_until(last_activity + self.relay_policy.idle_timeout) => {
+                    break RelayShutdownReason::ReaderTimeout;
+                }
+            }
+        };
+
+        let duration = start_time.elapsed();
+        info!(
+            "UDP association closed: {:?}, upstream {} bytes/{} datagrams, downstream {} bytes/{} datagrams, {:?}, CTX={}",
+            shutdown_reason,
+            upstream.total_bytes,
+            upstream.event_count,
+            downstream.total_bytes,
+            downstream.event_count,
+            duration,
+            self.tunnel_ctx
+        );
+
+        let stats = |accounting:UdpAccounting| RelayStats {
+            shutdown_reason: shutdown_reason.clone(),
+            total_bytes: accounting.total_bytes,
+            event_count: accounting.event_count,
+            duration,
+        };
+        Ok((stats(upstream),stats(downstream)))
+    }
+
+    /// Unwraps `RSV FRAG ATYP DST.ADDR DST.PORT DATA` and sends DATA to the target,
+    /// or queues it while the target is being opened.
+    /// Returns the payload size, or `None` if the datagram wasn't sent.
+    async fn forward_to_target(&mut self, datagram:&[u8], from:SocketAddr) -> Option<usize> {
+        if Some(from.ip()) != self.client_ip && self.client_ip.is_some() {
+            debug!("Dropping datagram from unexpected source {}, CTX={}", from, self.tunnel_ctx);
+            return None;
+        }
+        self.client_udp_addr = Some(from);
+
+        if datagram.len() < 4 || datagram[2] != 0 {
+            // fragmentation is optional in RFC 1928, we don't support it
+            debug!("Dropping malformed or fragmented datagram, CTX={}", self.tunnel_ctx);
+            return None;
+        }
+
+        let (target,header_len) = match parse_address(&datagram[3..]) {
+            Ok(Some(address)) => address,
+            _ => {
+                debug!("Dropping datagram with bad address, CTX={}", self.tunnel_ctx);
+                return None;
+            }
+        };
+
+        let allowed_targets = self.target_config.allowed_targets_for(self.tunnel_ctx.user(), self.tunnel_ctx.server_name());
+        if !allowed_targets.is_match(&target) {
+            debug!("Target {} is not allowed, only allowed is {}, CTX={}",
+                target,
+                allowed_targets,
+                self.tunnel_ctx
+            );
+            return None;
+        }
+
+        let payload = &datagram[3 + header_len..];
+        let destinations = self.destinations.len();
+        match self.destinations.get_mut(&target) {
+            Some(Destination::Open(socket)) => send(socket, payload, &target, &self.tunnel_ctx).await,
+            Some(Destination::Opening(queued)) => {
+                if queued.len() < UPSTREAM_QUEUE_SIZE {
+                    queued.push(payload.to_vec());
+                }
+                None
+            }
+            None if destinations >= MAX_DESTINATIONS => {
+                debug!("Dropping datagram to {}, already {} destinations, CTX={}", target, MAX_DESTINATIONS, self.tunnel_ctx);
+                None
+            }
+            None => {
+                self.opening.spawn(open_destination(
+                    self.target_config.clone(),
+                    self.dns_resolver.clone(),
+                    self.tunnel_ctx.clone(),
+                    target.clone(),
+                ));
+                self.destinations.insert(target, Destination::Opening(vec![payload.to_vec()]));
+                None
+            }
+        }
+    }
+
+    /// Starts receiving from a newly opened destination and sends what was queued for it.
+    /// Returns the sizes of the payloads sent.
+    async fn opened(&mut self, requested:String, opened:Opened) -> Vec<usize> {
+        let Some((socket,target_addr)) = opened else {
+            // the next datagram to it tries again
+            self.destinations.remove(&requested);
+            return vec![];
+        };
+
+        let receiver = socket.clone();
+        let datagrams = self.upstream_sender.clone();
+        self.upstream_receivers.spawn(async move {
+            let mut buffer = vec![0u8;MAX_DATAGRAM_SIZE];
+            loop {
+                match receiver.recv(&mut buffer).await {
+                    Ok(len) => {
+                        if datagrams.send((buffer[..len].to_vec(),target_addr)).await.is_err() {
+                            break;
+                        }
+                    }
+                    // ICMP unreachable for an earlier datagram, the destination may still answer
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let queued = match self.destinations.insert(requested.clone(), Destination::Open(socket.clone())) {
+            Some(Destination::Opening(queued)) => queued,
+            _ => vec![],
+        };
+        let mut sent = Vec::with_capacity(queued.len());
+        for payload in queued {
+            sent.extend(send(&socket, &payload, &requested, &self.tunnel_ctx).await);
+        }
+        sent
+    }
+
+    /// Wraps a target's datagram with the SOCKS5 UDP header and sends it to the client.
+    async fn forward_to_client(&self, payload:&[u8], from:SocketAddr) -> Option<usize> {
+        let client = self.client_udp_addr?;
+
+        let mut datagram = BytesMut::with_capacity(payload.len() + 22);
+        datagram.extend_from_slice(&[0,0,0]);
+        encode_address(Self::canonical_addr(from), &mut datagram);
+        datagram.extend_from_slice(payload);
+
+        match self.client_socket.send_to(&datagram, client).await {
+            Ok(_) => Some(payload.len()),
+            Err(e) => {
+                debug!("Failed to send datagram to client {}: {}, CTX={}", client, e, self.tunnel_ctx);
+                None
+            }
+        }
+    }
+
+    fn canonical_addr(addr:SocketAddr) -> SocketAddr {
+        SocketAddr::new(addr.ip().to_canonical(),addr.port())
+    }
+}
+
+async fn send(socket:&UdpSocket, payload:&[u8], target:&str, ctx:&TunnelCtx) -> Option<usize> {
+    match socket.send(payload).await {
+        Ok(sent) => Some(sent),
+        Err(e) => {
+            debug!("Failed to send datagram to {}: {}, CTX={}", target, e, ctx);
+            None
+        }
+    }
+}
+
+/// Resolves `requested` and opens a socket connected to the first address the
+/// IP family policy and the source allow, bound and tuned for the route.
+async fn open_destination<R: DnsResolver>(
+    target_config:TargetConnectionConfig,
+    mut dns_resolver:R,
+    ctx:TunnelCtx,
+    requested:String,
+) -> (String,Opened) {
+    // routes, sources and socket options follow what the client asked for
+    let ip_family = target_config.ip_family_for(&requested);
+    let target = match target_config.rewrite_target(&requested) {
+        Some(rewritten) => {
+            debug!("Rewrote UDP target {} to {}, CTX={}", requested, rewritten, ctx);
+            rewritten
+        }
+        None => requested.clone(),
+    };
+
+    let source = target_config.source_for(&requested, ctx.user());
+    let resolved = resolve_target(&target_config, &mut dns_resolver, &target).await;
+    let addr = resolved.map(|addrs| {
+        ip_family
+            .apply(addrs)
+            .into_iter()
+            .find(|addr| source.is_none_or(|source| source.allows(addr)))
+    });
+    let target_addr = match addr {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            debug!("No {:?} addresses for UDP target {} the source can use, CTX={}", ip_family, target, ctx);
+            return (requested,None);
+        }
+        Err(e) => {
+            debug!("Failed to resolve UDP target {}: {}, CTX={}", target, e, ctx);
+            return (requested,None);
+        }
+    };
+
+    let options = target_config.socket_options_for(&requested);
+    match outbound::connect_udp(target_addr, source, options).await {
+        Ok(socket) => {
+            debug!("UDP socket {:?} connected to {} ({}), CTX={}", socket.local_addr(), target, target_addr, ctx);
+            (requested,Some((Arc::new(socket),target_addr)))
+        }
+        Err(e) => {
+            debug!("Failed to open UDP socket to {}: {}, CTX={}", target, e, ctx);
+            (requested,None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        configuration::{SourceConfig, TunnelConfig},
+        test_support::LiteralResolver,
+    };
+    use async_trait::async_trait;
+    use regex::Regex;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    /// Rotates its addresses on every lookup, like the caching resolver's round-robin.
+    #[derive(Clone)]
+    struct RotatingResolver {
+        addrs: Arc<Mutex<Vec<SocketAddr>>>,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl DnsResolver for RotatingResolver {
+        async fn resolve(&mut self, _target:&str) -> io::Result<Vec<SocketAddr>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let mut addrs = self.addrs.lock().unwrap();
+            addrs.rotate_left(1);
+            Ok(addrs.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_datagram_round_trip() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (sources,mut seen_source) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut buffer = [0u8;64];
+            let (len,from) = echo.recv_from(&mut buffer).await.unwrap();
+            sources.send(from.ip()).unwrap();
+            // the association never sent to the stranger, its datagram goes nowhere
+            stranger.send_to(b"spoofed", from).await.unwrap();
+            echo.send_to(&buffer[..len], from).await.unwrap();
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ctx = crate::tunnel::TunnelCtxBuilder::default()
+            .id(1)
+            .client_addr(Some(client.local_addr().unwrap()))
+            .local_addr(Some("127.0.0.1:1080".parse().unwrap()))
+            .build()
+            .unwrap();
+        let mut target_config = TunnelConfig::default().target_connection;
+        target_config.allowed_targets = Regex::new(r"^127\.0\.0\.1:\d+$").unwrap();
+        target_config.source = Some(SourceConfig {
+            addresses: vec!["127.0.0.2".parse().unwrap()],
+            ..Default::default()
+        });
+        let association = UdpAssociation::bind(
+            ctx,
+            target_config,
+            LiteralResolver,
+            RelayPolicy { idle_timeout: Duration::from_secs(1), min_rate_bpm: 0, max_rate_bps: 1000 },
+        ).await.unwrap();
+        let relay_addr = association.local_addr().unwrap();
+
+        let (control,_control_peer) = tokio::io::duplex(64);
+        let relay = tokio::spawn(association.relay(control));
+
+        let mut datagram = BytesMut::from(&[0u8,0,0][..]);
+        encode_address(echo_addr, &mut datagram);
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        let mut buffer = [0u8;64];
+        let (len,_) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], &datagram[..]);
+        assert_eq!(seen_source.try_recv().unwrap().to_string(), "127.0.0.2");
+
+        let (upstream,downstream) = relay.await.unwrap().unwrap();
+        assert_eq!(upstream.shutdown_reason, RelayShutdownReason::ReaderTimeout);
+        assert_eq!((upstream.total_bytes,downstream.total_bytes), (4,4));
+    }
+
+    #[tokio::test]
+    async fn test_flow_keeps_its_address() {
+        let (received,mut datagrams) = mpsc::unbounded_channel();
+        let mut addrs = vec![];
+        for _ in 0..2 {
+            let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = server.local_addr().unwrap();
+            addrs.push(addr);
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0u8;64];
+                loop {
+                    let (len,from) = server.recv_from(&mut buffer).await.unwrap();
+                    received.send((addr,from,buffer[..len].to_vec())).unwrap();
+                }
+            });
+        }
+        let resolver = RotatingResolver {
+            addrs: Arc::new(Mutex::new(addrs)),
+            lookups: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ctx = crate::tunnel::TunnelCtxBuilder::default()
+            .id(1)
+            .client_addr(Some(client.local_addr().unwrap()))
+            .local_addr(Some("127.0.0.1:1080".parse().unwrap()))
+            .build()
+            .unwrap();
+        let mut target_config = TunnelConfig::default().target_connection;
+        target_config.allowed_targets = Regex::new(r"^quic\.example:443$").unwrap();
+        let association = UdpAssociation::bind(
+            ctx,
+            target_config,
+            resolver.clone(),
+            RelayPolicy { idle_timeout: Duration::from_secs(1), min_rate_bpm: 0, max_rate_bps: 1000 },
+        ).await.unwrap();
+        let relay_addr = association.local_addr().unwrap();
+        let (control,_control_peer) = tokio::io::duplex(64);
+        tokio::spawn(association.relay(control));
+
+        for payload in [&b"one"[..], b"two", b"three"] {
+            let mut datagram = BytesMut::from(&[0u8,0,0,0x03,12][..]);
+            datagram.extend_from_slice(b"quic.example");
+            datagram.extend_from_slice(&443u16.to_be_bytes());
+            datagram.extend_from_slice(payload);
+            client.send_to(&datagram, relay_addr).await.unwrap();
+        }
+
+        // the first datagram picks the server and the socket, the rest follow it
+        let (server,source,payload) = datagrams.recv().await.unwrap();
+        assert_eq!(payload, b"one");
+        for expected in [&b"two"[..], b"three"] {
+            assert_eq!(datagrams.recv().await.unwrap(), (server,source,expected.to_vec()));
+        }
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,75 @@
+use openssl::{
+    asn1::Asn1Time,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+};
+
+#[derive(Clone,Copy)]
+pub enum KeyType {
+    Rsa,
+    Ecdsa,
+    Ed25519,
+}
+
+/// Self-signed certificate for `name` and its key, both PEM, the key in the
+/// encoding tools usually write for its type: PKCS#1, SEC1 or PKCS#8.
+pub fn self_signed_with(name:&str, key_type:KeyType) -> (Vec<u8>,Vec<u8>) {
+    let (key,key_pem,digest): (PKey<Private>,Vec<u8>,MessageDigest) = match key_type {
+        KeyType::Rsa => {
+            let rsa = Rsa::generate(2048).unwrap();
+            let pem = rsa.private_key_to_pem().unwrap();
+            (PKey::from_rsa(rsa).unwrap(),pem,MessageDigest::sha256())
+        }
+        KeyType::Ecdsa => {
+            let ec = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+            let pem = ec.private_key_to_pem().unwrap();
+            (PKey::from_ec_key(ec).unwrap(),pem,MessageDigest::sha256())
+        }
+        KeyType::Ed25519 => {
+            let key = PKey::generate_ed25519().unwrap();
+            let pem = key.private_key_to_pem_pkcs8().unwrap();
+            (key,pem,MessageDigest::null())
+        }
+    };
+
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", name).unwrap();
+    let subject = subject.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&subject).unwrap();
+    cert.set_issuer_name(&subject).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new().dns(name).build(&cert.x509v3_context(None, None)).unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, digest).unwrap();
+
+    (cert.build().to_pem().unwrap(),key_pem)
+}
+
+/// RSA certificate for `name` with a PKCS#8 key.
+pub fn self_signed(name:&str) -> (Vec<u8>,Vec<u8>) {
+    let (cert,key) = self_signed_with(name, KeyType::Rsa);
+    let key = PKey::private_key_from_pem(&key).unwrap().private_key_to_pem_pkcs8().unwrap();
+    (cert,key)
+}
+
+/// RSA certificate for `name` and its key as a PKCS#12 bundle.
+pub fn self_signed_pkcs12(name:&str, password:&str) -> (Vec<u8>,Vec<u8>) {
+    let (cert,key) = self_signed(name);
+    let pkcs12 = Pkcs12::builder()
+        .name(name)
+        .pkey(&PKey::private_key_from_pem(&key).unwrap())
+        .cert(&X509::from_pem(&cert).unwrap())
+        .build2(password)
+        .unwrap();
+    (cert,pkcs12.to_der().unwrap())
+}
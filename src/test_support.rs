@@ -0,0 +1,25 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use tempfile::NamedTempFile;
+use tokio::io;
+
+use crate::proxy_target::DnsResolver;
+
+/// Resolves nothing but literal `ip:port` targets, tests never touch DNS.
+#[derive(Clone)]
+pub struct LiteralResolver;
+
+#[async_trait]
+impl DnsResolver for LiteralResolver {
+    async fn resolve(&mut self, target:&str) -> io::Result<Vec<SocketAddr>> {
+        target.parse().map(|addr| vec![addr]).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+}
+
+/// A file holding `content`, removed when dropped.
+pub fn temp_file(content:&[u8]) -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), content).unwrap();
+    file
+}
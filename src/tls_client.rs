@@ -0,0 +1,20 @@
+// TLS the proxy originates, towards targets, parent proxies and DNS servers,
+// on the same backend as the HTTPS listener.
+use std::sync::OnceLock;
+
+use tokio::io;
+
+#[cfg(not(feature = "rustls"))]
+pub use crate::tls_openssl::{connect, connector, ClientStream, Connector};
+#[cfg(feature = "rustls")]
+pub use crate::tls_rustls::{connect, connector, ClientStream, Connector};
+
+/// The connector trusting the system roots, built once and shared.
+pub fn system_connector() -> io::Result<Connector> {
+    static SYSTEM: OnceLock<Connector> = OnceLock::new();
+    if let Some(connector) = SYSTEM.get() {
+        return Ok(connector.clone());
+    }
+    let connector = connector(None, None)?;
+    Ok(SYSTEM.get_or_init(|| connector).clone())
+}
@@ -0,0 +1,176 @@
+use std::pin::Pin;
+
+use log::error;
+use openssl::{
+    nid::Nid,
+    pkey::PKey,
+    ssl::{Ssl, SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode},
+    stack::Stack,
+    x509::{
+        store::{X509Lookup, X509StoreBuilder},
+        verify::X509VerifyFlags,
+        X509, X509Name, X509Ref,
+    },
+};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio_native_tls::{TlsConnector, TlsStream};
+use tokio_openssl::SslStream;
+
+use crate::configuration::{ClientAuthConfig, ClientIdentityField, TlsIdentity};
+
+// client certificates are verified, resumed sessions have to be ours
+const SESSION_ID_CONTEXT: &[u8] = b"http-tunnel";
+
+pub type Acceptor = SslAcceptor;
+pub type ServerStream<S> = SslStream<S>;
+pub type Connector = TlsConnector;
+pub type ClientStream<S> = TlsStream<S>;
+
+pub fn acceptor(identity:&TlsIdentity, client_auth:Option<&ClientAuthConfig>) -> io::Result<SslAcceptor> {
+    let tls_error = |e| {
+        error!("Failed to create TLS acceptor: {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(tls_error)?;
+
+    let chain = X509::stack_from_pem(identity.chain.as_bytes()).map_err(tls_error)?;
+    let Some((certificate,intermediates)) = chain.split_first() else {
+        error!("No certificate in the TLS certificate chain");
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    };
+    builder.set_certificate(certificate).map_err(tls_error)?;
+    for intermediate in intermediates {
+        builder.add_extra_chain_cert(intermediate.clone()).map_err(tls_error)?;
+    }
+    let key = PKey::private_key_from_pem(identity.key.as_bytes()).map_err(tls_error)?;
+    builder.set_private_key(&key).map_err(tls_error)?;
+    // so a key that doesn't match fails now rather than on every client connection
+    builder.check_private_key().map_err(|e| {
+        error!("TLS self-check failed, does the key match the certificate? {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    })?;
+
+    if let Some(client_auth) = client_auth {
+        verify_clients(&mut builder, client_auth)?;
+    }
+    Ok(builder.build())
+}
+
+fn verify_clients(builder:&mut SslAcceptorBuilder, client_auth:&ClientAuthConfig) -> io::Result<()> {
+    let tls_error = |e| {
+        error!("Failed to set up client certificate verification: {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+    let bundle = std::fs::read(&client_auth.ca_file).map_err(|e| {
+        error!("Failed to read client CA bundle {}: {}", client_auth.ca_file, e);
+        e
+    })?;
+    let cas = X509::stack_from_pem(&bundle).map_err(tls_error)?;
+    if cas.is_empty() {
+        error!("No certificate in client CA bundle {}", client_auth.ca_file);
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut store = X509StoreBuilder::new().map_err(tls_error)?;
+    let mut ca_names = Stack::<X509Name>::new().map_err(tls_error)?;
+    for ca in cas {
+        ca_names.push(ca.subject_name().to_owned().map_err(tls_error)?).map_err(tls_error)?;
+        store.add_cert(ca).map_err(tls_error)?;
+    }
+    if !client_auth.crl_files.is_empty() {
+        let lookup = store.add_lookup(X509Lookup::file()).map_err(tls_error)?;
+        for crl_file in &client_auth.crl_files {
+            lookup.load_crl_file(crl_file, SslFiletype::PEM).map_err(|e| {
+                error!("Failed to load CRL {}: {}", crl_file, e);
+                io::Error::from(io::ErrorKind::InvalidInput)
+            })?;
+        }
+        store.set_flags(X509VerifyFlags::CRL_CHECK).map_err(tls_error)?;
+    }
+
+    builder.set_verify_cert_store(store.build()).map_err(tls_error)?;
+    builder.set_client_ca_list(ca_names);
+    builder.set_session_id_context(SESSION_ID_CONTEXT).map_err(tls_error)?;
+    let mode = match client_auth.required {
+        true => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        false => SslVerifyMode::PEER,
+    };
+    builder.set_verify(mode);
+    Ok(())
+}
+
+/// Runs the server side of the handshake.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(acceptor:&SslAcceptor, stream:S) -> io::Result<SslStream<S>> {
+    let mut stream = Ssl::new(acceptor.context())
+        .and_then(|ssl| SslStream::new(ssl, stream))
+        .map_err(|e| {
+            error!("Failed to create TLS session: {}", e);
+            io::Error::from(io::ErrorKind::Other)
+        })?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string()))?;
+    Ok(stream)
+}
+
+/// Verifies servers against the PEM `ca_bundle`, or the system roots without one,
+/// and presents `identity` to servers asking for a client certificate.
+pub fn connector(ca_bundle:Option<&str>, identity:Option<&TlsIdentity>) -> io::Result<TlsConnector> {
+    let tls_error = |e:&dyn std::fmt::Display| {
+        error!("Failed to create TLS connector: {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(bundle) = ca_bundle {
+        let cas = X509::stack_from_pem(bundle.as_bytes()).map_err(|e| tls_error(&e))?;
+        if cas.is_empty() {
+            error!("No certificate in the CA bundle");
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        builder.disable_built_in_roots(true);
+        for ca in cas {
+            let der = ca.to_der().map_err(|e| tls_error(&e))?;
+            builder.add_root_certificate(native_tls::Certificate::from_der(&der).map_err(|e| tls_error(&e))?);
+        }
+    }
+    if let Some(identity) = identity {
+        let identity = native_tls::Identity::from_pkcs8(identity.chain.as_bytes(), identity.key.as_bytes()).map_err(|e| tls_error(&e))?;
+        builder.identity(identity);
+    }
+    builder.build().map(TlsConnector::from).map_err(|e| tls_error(&e))
+}
+
+/// Runs the client side of the handshake, verifying the server is `server_name`.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(connector:&TlsConnector, server_name:&str, stream:S) -> io::Result<TlsStream<S>> {
+    connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string()))
+}
+
+/// CN or SAN of the verified client certificate, `field` first.
+pub fn peer_identity<S>(stream:&SslStream<S>, field:ClientIdentityField) -> Option<String> {
+    let certificate = stream.ssl().peer_certificate()?;
+    match field {
+        ClientIdentityField::CommonName => common_name(&certificate).or_else(|| subject_alt_name(&certificate)),
+        ClientIdentityField::SubjectAltName => subject_alt_name(&certificate).or_else(|| common_name(&certificate)),
+    }
+}
+
+fn common_name(certificate:&X509Ref) -> Option<String> {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| std::str::from_utf8(entry.data().as_slice()).ok())
+        .map(str::to_string)
+}
+
+fn subject_alt_name(certificate:&X509Ref) -> Option<String> {
+    certificate
+        .subject_alt_names()?
+        .iter()
+        .find_map(|name| name.uri().or(name.dnsname()).or(name.email()).map(str::to_string))
+}
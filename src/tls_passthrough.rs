@@ -0,0 +1,197 @@
+use log::{debug, error};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    time::timeout,
+};
+
+use crate::{
+    circuit_breaker::CircuitBreakers,
+    client_hello::{read_client_hello, PrefixedStream},
+    configuration::{ClientConnectionConfig, TargetConnectionConfig},
+    http_tunnel_codec::{HttpTunnelTarget, HttpTunnelTargetBuilder},
+    proxy_target::{DnsResolver, SimpleTcpConnector},
+    tunnel::{connect_to_target, relay_connections, TargetProtocol, TunnelCtx},
+};
+
+/// Forwards a TLS connection, ClientHello included, to `<SNI>:<port>` without
+/// terminating it. Rewrite rules map server names to other backends.
+pub async fn tunnel_stream<C: AsyncRead + AsyncWrite + Send + Unpin + 'static, R: DnsResolver + Clone + Send + Sync + 'static>(
+    client_config:&ClientConnectionConfig,
+    target_config:&TargetConnectionConfig,
+    mut client_connection:C,
+    port:u16,
+    ctx:TunnelCtx,
+    dns_resolver:R,
+    circuit_breakers:CircuitBreakers,
+) -> io::Result<()> {
+    let (client_hello,server_name) = match timeout(client_config.initiation_timeout, read_client_hello(&mut client_connection)).await {
+        Ok(Ok(read)) => read,
+        Ok(Err(e)) => {
+            error!("Failed to read ClientHello: {}, CTX={}", e, ctx);
+            return Ok(());
+        }
+        Err(_) => {
+            error!("Timeout reading ClientHello, CTX={}", ctx);
+            return Ok(());
+        }
+    };
+
+    let Some(server_name) = server_name.filter(|name| is_host_name(name)) else {
+        error!("ClientHello without a usable SNI, CTX={}", ctx);
+        return Ok(());
+    };
+    let ctx = ctx.with_server_name(Some(server_name.clone()));
+
+    let target = format!("{}:{}", server_name, port);
+    // the SNI names the target here, not one of our listener names
+    let allowed_targets = target_config.allowed_targets_for(ctx.user(), None);
+    if !allowed_targets.is_match(&target) {
+        error!("Target {} doesn't match allowed targets {}, CTX={}", target, allowed_targets, ctx);
+        return Ok(());
+    }
+
+    let target: HttpTunnelTarget = HttpTunnelTargetBuilder::default()
+        .target(target)
+        .nugget(None)
+        .protocol(TargetProtocol::Tls)
+        .build()
+        .expect("HttpTunnelTargetBuilder failed");
+    let mut connector: SimpleTcpConnector<HttpTunnelTarget, R> =
+        SimpleTcpConnector::new(dns_resolver, target_config.clone(), ctx.clone(), circuit_breakers);
+
+    let Ok(destination) = connect_to_target(&mut connector, &target, target_config, &ctx).await else {
+        return Ok(());
+    };
+    debug!("Forwarding TLS to {}, CTX={}", target, ctx);
+
+    relay_connections(
+        PrefixedStream::new(client_hello, client_connection),
+        destination,
+        ctx,
+        client_config.relay_policy.clone(),
+        target_config.relay_policy.clone(),
+    )
+    .await?;
+    Ok(())
+}
+
+// the SNI becomes part of the target, nothing but a DNS name may get there
+fn is_host_name(name:&str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        configuration::{RewriteRule, TunnelConfig, UpstreamTlsConfig},
+        test_certs::self_signed,
+        test_support::LiteralResolver,
+    };
+    use native_tls::{Certificate, Identity};
+    use regex::Regex;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_native_tls::{TlsAcceptor, TlsConnector};
+
+    /// TLS echo server for `name`, returns its address and certificate.
+    async fn tls_echo_server(name:&str) -> (String,Vec<u8>) {
+        let (cert,key) = self_signed(name);
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(Identity::from_pkcs8(&cert, &key).unwrap()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream,_) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await.unwrap();
+                    let mut buf = [0u8;4];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                });
+            }
+        });
+        (addr,cert)
+    }
+
+    /// Client handshake for `server_name` through the passthrough.
+    async fn connect_through(target_config:&TargetConnectionConfig, server_name:&str, cert:&[u8]) -> io::Result<tokio_native_tls::TlsStream<io::DuplexStream>> {
+        let (client,proxied) = duplex(65536);
+        let client_config = TunnelConfig::default().client_connection;
+        let target_config = target_config.clone();
+        tokio::spawn(async move {
+            tunnel_stream(&client_config, &target_config, proxied, 443, TunnelCtx::default(), LiteralResolver, CircuitBreakers::new(None)).await
+        });
+
+        // the client verifies the backend itself, the proxy never sees plain text
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(Certificate::from_pem(cert).unwrap())
+            .build()
+            .unwrap();
+        TlsConnector::from(connector)
+            .connect(server_name, client)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_forward_by_sni() {
+        let (backend,cert) = tls_echo_server("partner.example").await;
+        let mut target_config = TunnelConfig::default().target_connection;
+        target_config.allowed_targets = Regex::new(r"^partner\.example:443$").unwrap();
+        target_config.rewrites = vec![RewriteRule {
+            target: Regex::new(r"^partner\.example:443$").unwrap(),
+            rewrite_to: backend,
+        }];
+
+        let mut stream = connect_through(&target_config, "partner.example", &cert).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8;4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+
+        assert!(connect_through(&target_config, "blocked.example", &cert).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_upstream_tls() {
+        let (backend,cert) = tls_echo_server("partner.example").await;
+        let mut target_config = TunnelConfig::default().target_connection;
+        target_config.allowed_targets = Regex::new(r"^partner\.example:443$").unwrap();
+        target_config.rewrites = vec![RewriteRule {
+            target: Regex::new(r"^partner\.example:443$").unwrap(),
+            rewrite_to: backend,
+        }];
+        // meant for plain TCP clients, the passthrough has to leave it out
+        target_config.upstream_tls = Some(UpstreamTlsConfig {
+            server_name: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+        });
+
+        let mut stream = connect_through(&target_config, "partner.example", &cert).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8;4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+
+    #[test]
+    fn test_is_host_name() {
+        assert!(is_host_name("partner.example"));
+        assert!(is_host_name("_acme-challenge.example"));
+        assert!(!is_host_name(""));
+        assert!(!is_host_name("partner.example:8443"));
+        assert!(!is_host_name("partner..example"));
+        assert!(!is_host_name("10.0.0.1/8"));
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::{Arc, LazyLock};
+
+use log::{debug, error};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, CertificateRevocationListDer, PrivateKeyDer, ServerName},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server::TlsStream, TlsAcceptor, TlsConnector};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::configuration::{ClientAuthConfig, ClientIdentityField, TlsIdentity};
+
+pub type Acceptor = TlsAcceptor;
+pub type ServerStream<S> = TlsStream<S>;
+pub type Connector = TlsConnector;
+pub type ClientStream<S> = client::TlsStream<S>;
+
+// read once, the system store doesn't change under a running proxy
+static SYSTEM_ROOTS: LazyLock<Arc<RootCertStore>> = LazyLock::new(|| {
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        debug!("Skipping system CA certificates: {}", e);
+    }
+    let mut roots = RootCertStore::empty();
+    let (added,ignored) = roots.add_parsable_certificates(native.certs);
+    debug!("Loaded {} system CA certificates, ignored {}", added, ignored);
+    Arc::new(roots)
+});
+
+pub fn acceptor(identity:&TlsIdentity, client_auth:Option<&ClientAuthConfig>) -> io::Result<TlsAcceptor> {
+    let tls_error = |e:rustls::Error| {
+        error!("Failed to create TLS acceptor: {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+    let pem_error = |e:rustls::pki_types::pem::Error| {
+        error!("Failed to create TLS acceptor: {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+
+    let chain = CertificateDer::pem_slice_iter(identity.chain.as_bytes())
+        .collect::<Result<Vec<_>,_>>()
+        .map_err(pem_error)?;
+    if chain.is_empty() {
+        error!("No certificate in the TLS certificate chain");
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let key = PrivateKeyDer::from_pem_slice(identity.key.as_bytes()).map_err(pem_error)?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match client_auth {
+        Some(client_auth) => builder.with_client_cert_verifier(verify_clients(client_auth, provider)?),
+        None => builder.with_no_client_auth(),
+    };
+    // fails now rather than on every client connection when the key doesn't match
+    let config = builder.with_single_cert(chain, key).map_err(|e| {
+        error!("TLS self-check failed, does the key match the certificate? {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    })?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn verify_clients(client_auth:&ClientAuthConfig, provider:Arc<CryptoProvider>) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let read = |file_path:&str| std::fs::read(file_path).map_err(|e| {
+        error!("Failed to read {}: {}", file_path, e);
+        e
+    });
+    let invalid = |file_path:&str, e:&dyn std::fmt::Display| {
+        error!("Failed to set up client certificate verification with {}: {}", file_path, e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_slice_iter(&read(&client_auth.ca_file)?) {
+        let ca = ca.map_err(|e| invalid(&client_auth.ca_file, &e))?;
+        roots.add(ca).map_err(|e| invalid(&client_auth.ca_file, &e))?;
+    }
+    if roots.is_empty() {
+        error!("No certificate in client CA bundle {}", client_auth.ca_file);
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut crls = vec![];
+    for crl_file in &client_auth.crl_files {
+        for crl in CertificateRevocationListDer::pem_slice_iter(&read(crl_file)?) {
+            crls.push(crl.map_err(|e| invalid(crl_file, &e))?);
+        }
+    }
+
+    // like OpenSSL's CRL_CHECK, only the client certificate itself
+    let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .with_crls(crls)
+        .only_check_end_entity_revocation();
+    if !client_auth.required {
+        verifier = verifier.allow_unauthenticated();
+    }
+    verifier.build().map_err(|e| invalid(&client_auth.ca_file, &e))
+}
+
+/// Runs the server side of the handshake.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(acceptor:&TlsAcceptor, stream:S) -> io::Result<TlsStream<S>> {
+    acceptor
+        .accept(stream)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string()))
+}
+
+/// Verifies servers against the PEM `ca_bundle`, or the system roots without one,
+/// and presents `identity` to servers asking for a client certificate.
+pub fn connector(ca_bundle:Option<&str>, identity:Option<&TlsIdentity>) -> io::Result<TlsConnector> {
+    let tls_error = |e:&dyn std::fmt::Display| {
+        error!("Failed to create TLS connector: {}", e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    };
+
+    let roots = match ca_bundle {
+        Some(bundle) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_slice_iter(bundle.as_bytes()) {
+                roots.add(ca.map_err(|e| tls_error(&e))?).map_err(|e| tls_error(&e))?;
+            }
+            Arc::new(roots)
+        }
+        None => SYSTEM_ROOTS.clone(),
+    };
+    if roots.is_empty() {
+        error!("No trusted CA certificate to verify servers with");
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| tls_error(&e))?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some(identity) => {
+            let chain = CertificateDer::pem_slice_iter(identity.chain.as_bytes())
+                .collect::<Result<Vec<_>,_>>()
+                .map_err(|e| tls_error(&e))?;
+            let key = PrivateKeyDer::from_pem_slice(identity.key.as_bytes()).map_err(|e| tls_error(&e))?;
+            builder.with_client_auth_cert(chain, key).map_err(|e| tls_error(&e))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Runs the client side of the handshake, verifying the server is `server_name`.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(connector:&TlsConnector, server_name:&str, stream:S) -> io::Result<client::TlsStream<S>> {
+    let server_name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+        error!("Bad TLS server name {}: {}", server_name, e);
+        io::Error::from(io::ErrorKind::InvalidInput)
+    })?;
+    connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e.to_string()))
+}
+
+/// CN or SAN of the verified client certificate, `field` first.
+pub fn peer_identity<S>(stream:&TlsStream<S>, field:ClientIdentityField) -> Option<String> {
+    let der = stream.get_ref().1.peer_certificates()?.first()?;
+    let (_,certificate) = X509Certificate::from_der(der).ok()?;
+    match field {
+        ClientIdentityField::CommonName => common_name(&certificate).or_else(|| subject_alt_name(&certificate)),
+        ClientIdentityField::SubjectAltName => subject_alt_name(&certificate).or_else(|| common_name(&certificate)),
+    }
+}
+
+fn common_name(certificate:&X509Certificate) -> Option<String> {
+    certificate
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|name| name.as_str().ok())
+        .map(|name| name.to_string())
+}
+
+fn subject_alt_name(certificate:&X509Certificate) -> Option<String> {
+    certificate
+        .subject_alternative_name()
+        .ok()??
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::URI(name) | GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => Some(name.to_string()),
+            _ => None,
+        })
+}
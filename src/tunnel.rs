@@ -1,10 +1,79 @@
+use std::{fmt, net::SocketAddr};
+
+use async_trait::async_trait;
 use derive_builder::Builder;
+use futures::{SinkExt, StreamExt};
+use log::{debug, error};
 use serde::Serialize;
+use tokio::{io::{self, AsyncRead, AsyncWrite, AsyncWriteExt}, time::timeout};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{
+    configuration::{ClientConnectionConfig, TargetConnectionConfig},
+    proxy_target::Nugget,
+    relay::{RelayBuilder, RelayPolicy, RelayStats},
+};
 
 
-#[derive(Builder,Copy,Clone,Default,Serialize)]
+#[derive(Builder,Clone,Default,Serialize)]
 pub struct TunnelCtx {
     id: u128,
+    // address of the peer that opened the tunnel
+    #[builder(default)]
+    client_addr: Option<SocketAddr>,
+    // address of our listener the peer connected to
+    #[builder(default)]
+    local_addr: Option<SocketAddr>,
+    // authenticated user, if the front-end has any authentication
+    #[builder(default)]
+    user: Option<String>,
+    // SNI the client asked for on a TLS listener
+    #[builder(default)]
+    server_name: Option<String>,
+}
+
+impl TunnelCtx {
+    pub fn id(&self) -> u128 {
+        self.id
+    }
+
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn with_user(self, user:Option<String>) -> Self {
+        Self { user, ..self }
+    }
+
+    pub fn with_client_addr(self, client_addr:Option<SocketAddr>) -> Self {
+        Self { client_addr, ..self }
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    pub fn with_server_name(self, server_name:Option<String>) -> Self {
+        Self { server_name, ..self }
+    }
+}
+
+impl fmt::Display for TunnelCtx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{}",self.id)?;
+        if let Some(server_name) = &self.server_name {
+            write!(f," sni={}",server_name)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone,Eq,PartialEq, Debug, Serialize)]
@@ -17,8 +86,376 @@ pub enum EstablishTunnelResult {
     RequestTimeout,
     BadGateway,
     GatewayTimeout,
+    // the target's circuit breaker is open
+    ServiceUnavailable,
     TooManyRequest,
     ServerError,
 }
 
+impl From<io::Error> for EstablishTunnelResult {
+    fn from(_:io::Error) -> Self {
+        EstablishTunnelResult::ServerError
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub enum TargetProtocol {
+    #[default]
+    Tcp,
+    // datagrams are carried as capsules over the client stream (connect-udp)
+    Udp,
+    // the client's own TLS session over TCP, forwarded untouched (TLS passthrough)
+    Tls,
+}
+
+#[async_trait]
+pub trait TunnelTarget {
+    type Addr;
+
+    fn target_addr(&self) -> Self::Addr;
+    fn has_nugget(&self) -> bool;
+    fn target_nugget(&self) -> &Nugget;
+
+    fn target_protocol(&self) -> TargetProtocol {
+        TargetProtocol::Tcp
+    }
+}
+
+#[async_trait]
+pub trait TargetConnector {
+    type Target: TunnelTarget + Send + Sync + Sized;
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + Sized + 'static;
+
+    async fn connect(&mut self, target:&Self::Target) -> io::Result<Self::Stream>;
+}
+
+#[derive(Serialize)]
+pub struct TunnelStats {
+    tunnel_ctx: TunnelCtx,
+    result: EstablishTunnelResult,
+    upstream_stats: Option<RelayStats>,
+    downstream_stats: Option<RelayStats>,
+}
+
+impl TunnelStats {
+    /// Counts the nugget written before the relay started as upstream traffic.
+    fn with_nugget(mut self, nugget_bytes:usize) -> Self {
+        self.result = EstablishTunnelResult::OkWithNugget;
+        if let Some(upstream_stats) = self.upstream_stats.as_mut() {
+            upstream_stats.total_bytes += nugget_bytes;
+        }
+        self
+    }
+}
+
+pub struct ConnectionTunnel<H, C, T> {
+    tunnel_request_codec: Option<H>,
+    tunnel_ctx: TunnelCtx,
+    target_connector: T,
+    client: Option<C>,
+    client_config: ClientConnectionConfig,
+    target_config: TargetConnectionConfig,
+}
+
+impl<H, C, T> ConnectionTunnel<H, C, T>
+where
+    H: Decoder<Error = EstablishTunnelResult> + Encoder<EstablishTunnelResult>,
+    H::Item: TunnelTarget + Sized + fmt::Display + Send + Sync,
+    C: AsyncRead + AsyncWrite + Sized + Send + Unpin + 'static,
+    T: TargetConnector<Target = H::Item>,
+{
+    pub fn new(
+        handshake_codec:H,
+        target_connector:T,
+        client:C,
+        client_config:ClientConnectionConfig,
+        target_config:TargetConnectionConfig,
+        tunnel_ctx:TunnelCtx,
+    ) -> Self {
+        Self {
+            tunnel_request_codec: Some(handshake_codec),
+            target_connector,
+            tunnel_ctx,
+            client: Some(client),
+            client_config,
+            target_config,
+        }
+    }
+
+    pub async fn start(mut self) -> io::Result<TunnelStats> {
+        let client = self.client.take().expect("Client can be taken only once");
+
+        match self.establish_tunnel(client).await {
+            Ok((client,target,nugget_bytes)) => {
+                let stats = relay_connections(
+                    client,
+                    target,
+                    self.tunnel_ctx,
+                    self.client_config.relay_policy,
+                    self.target_config.relay_policy,
+                ).await?;
+                Ok(match nugget_bytes {
+                    Some(nugget_bytes) => stats.with_nugget(nugget_bytes),
+                    None => stats,
+                })
+            }
+            Err(result) => {
+                Ok(TunnelStats {
+                    tunnel_ctx: self.tunnel_ctx,
+                    result,
+                    upstream_stats: None,
+                    downstream_stats: None,
+                })
+            }
+        }
+    }
+
+    /// The client and target streams, with the size of the nugget already sent to the target.
+    async fn establish_tunnel(&mut self, client:C) -> Result<(C,T::Stream,Option<usize>),EstablishTunnelResult> {
+        let codec = self.tunnel_request_codec
+            .take()
+            .expect("establish_tunnel can be called only once");
+        let mut framed = Framed::new(client, codec);
+
+        let (response,target) = self.process_tunnel_request(&mut framed).await;
+
+        let response_sent = timeout(
+            self.client_config.initiation_timeout,
+            framed.send(response.clone()),
+        ).await.map(|r| r.is_ok()).unwrap_or(false);
+
+        match (response_sent,target) {
+            (true,Some((target,nugget_bytes))) => Ok((framed.into_inner(),target,nugget_bytes)),
+            (true,None) => Err(response),
+            (false,_) => Err(EstablishTunnelResult::RequestTimeout),
+        }
+    }
+
+    async fn process_tunnel_request(
+        &mut self,
+        framed:&mut Framed<C,H>,
+    ) -> (EstablishTunnelResult,Option<(T::Stream,Option<usize>)>) {
+        let request = timeout(self.client_config.initiation_timeout, framed.next()).await;
+
+        match request {
+            Err(_) => (EstablishTunnelResult::RequestTimeout,None),
+            Ok(None) => (EstablishTunnelResult::BadRequest,None),
+            Ok(Some(Err(e))) => (e,None),
+            Ok(Some(Ok(target))) => match connect_to_target(
+                &mut self.target_connector,
+                &target,
+                &self.target_config,
+                &self.tunnel_ctx,
+            ).await {
+                Ok(stream) if target.has_nugget() => self.forward_nugget(stream, &target).await,
+                Ok(stream) => (EstablishTunnelResult::Ok,Some((stream,None))),
+                Err(e) => (e,None),
+            },
+        }
+    }
+
+    /// Writes the request bytes that came with the tunnel request, the client
+    /// gets no response of ours: the target answers the request itself.
+    async fn forward_nugget(
+        &self,
+        mut stream:T::Stream,
+        target:&H::Item,
+    ) -> (EstablishTunnelResult,Option<(T::Stream,Option<usize>)>) {
+        let nugget = target.target_nugget().data();
+        match timeout(self.target_config.connection_timeout, stream.write_all(&nugget)).await {
+            Ok(Ok(())) => {
+                debug!("Forwarded {} bytes nugget to {}, CTX={}", nugget.len(), target, self.tunnel_ctx);
+                (EstablishTunnelResult::OkWithNugget,Some((stream,Some(nugget.len()))))
+            }
+            Ok(Err(e)) => {
+                error!("Failed to forward nugget to {}: {}, CTX={}", target, e, self.tunnel_ctx);
+                (EstablishTunnelResult::BadGateway,None)
+            }
+            Err(_) => {
+                error!("Timeout forwarding nugget to {}, CTX={}", target, self.tunnel_ctx);
+                (EstablishTunnelResult::GatewayTimeout,None)
+            }
+        }
+    }
+}
+
+/// Connects to the target within `connection_timeout`,
+/// mapping failures to the result reported back to the client.
+pub async fn connect_to_target<T>(
+    connector:&mut T,
+    target:&T::Target,
+    target_config:&TargetConnectionConfig,
+    ctx:&TunnelCtx,
+) -> Result<T::Stream,EstablishTunnelResult>
+where
+    T: TargetConnector,
+    T::Target: fmt::Display,
+{
+    debug!("Establishing tunnel to target {}, CTX={}", target, ctx);
+
+    match timeout(target_config.connection_timeout, connector.connect(target)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => {
+            error!("Failed to connect to target {}: {}, CTX={}", target, e, ctx);
+            // parent proxies and circuit breakers report their verdict through the error kind
+            Err(match e.kind() {
+                io::ErrorKind::PermissionDenied => EstablishTunnelResult::Forbidden,
+                io::ErrorKind::QuotaExceeded => EstablishTunnelResult::TooManyRequest,
+                io::ErrorKind::TimedOut => EstablishTunnelResult::GatewayTimeout,
+                io::ErrorKind::ResourceBusy => EstablishTunnelResult::ServiceUnavailable,
+                _ => EstablishTunnelResult::BadGateway,
+            })
+        }
+        Err(_) => {
+            error!("Timeout connecting to target {}, CTX={}", target, ctx);
+            Err(EstablishTunnelResult::GatewayTimeout)
+        }
+    }
+}
+
+pub async fn relay_connections<C, T>(
+    client:C,
+    target:T,
+    ctx:TunnelCtx,
+    downstream_relay_policy:RelayPolicy,
+    upstream_relay_policy:RelayPolicy,
+) -> io::Result<TunnelStats>
+where
+    C: AsyncRead + AsyncWrite + Send + 'static,
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (client_recv,client_send) = io::split(client);
+    let (target_recv,target_send) = io::split(target);
+
+    let upstream_relay = RelayBuilder::default()
+        .name("Upstream")
+        .tunnel_ctx(ctx.to_string())
+        .relay_policy(upstream_relay_policy)
+        .build()
+        .expect("RelayBuilder failed");
+
+    let downstream_relay = RelayBuilder::default()
+        .name("Downstream")
+        .tunnel_ctx(ctx.to_string())
+        .relay_policy(downstream_relay_policy)
+        .build()
+        .expect("RelayBuilder failed");
+
+    let upstream_task = tokio::spawn(async move {
+        upstream_relay.relay_data(client_recv,target_send).await
+    });
+    let downstream_task = tokio::spawn(async move {
+        downstream_relay.relay_data(target_recv,client_send).await
+    });
+
+    let upstream_stats = upstream_task.await??;
+    let downstream_stats = downstream_task.await??;
+
+    Ok(TunnelStats {
+        tunnel_ctx: ctx,
+        result: EstablishTunnelResult::Ok,
+        upstream_stats: Some(upstream_stats),
+        downstream_stats: Some(downstream_stats),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        configuration::TunnelConfig,
+        http_tunnel_codec::{HttpTunnelCodec, HttpTunnelCodecBuilder, HttpTunnelTarget},
+    };
+    use regex::Regex;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    /// Hands out `stream` for `target`, the only target the test expects.
+    struct DuplexConnector {
+        target: &'static str,
+        stream: Option<DuplexStream>,
+    }
+
+    #[async_trait]
+    impl TargetConnector for DuplexConnector {
+        type Target = HttpTunnelTarget;
+        type Stream = DuplexStream;
+
+        async fn connect(&mut self, target:&HttpTunnelTarget) -> io::Result<DuplexStream> {
+            assert_eq!(target.target_addr(), self.target);
+            Ok(self.stream.take().expect("connects once"))
+        }
+    }
+
+    fn http_tunnel(connector:DuplexConnector, client:DuplexStream) -> ConnectionTunnel<HttpTunnelCodec,DuplexStream,DuplexConnector> {
+        let config = TunnelConfig::default();
+        let codec = HttpTunnelCodecBuilder::default()
+            .tunnel_ctx(TunnelCtx::default())
+            .enabled_targets(Regex::new(r"^origin\.example:(80|443)$").unwrap())
+            .build()
+            .unwrap();
+        ConnectionTunnel::new(
+            codec,
+            connector,
+            client,
+            config.client_connection,
+            config.target_connection,
+            TunnelCtx::default(),
+        )
+    }
+
+    #[test]
+    fn test_ctx_display() {
+        let ctx = TunnelCtxBuilder::default().id(7).build().unwrap();
+        assert_eq!(ctx.to_string(), "7");
+        assert_eq!(ctx.with_server_name(Some("team-a.proxy.example".to_string())).to_string(), "7 sni=team-a.proxy.example");
+    }
 
+    #[tokio::test]
+    async fn test_connect() {
+        let (client,mut client_peer) = duplex(1024);
+        let (target,mut target_peer) = duplex(1024);
+        let tunnel = http_tunnel(DuplexConnector { target: "origin.example:443", stream: Some(target) }, client);
+        let tunnel = tokio::spawn(tunnel.start());
+
+        client_peer.write_all(b"CONNECT origin.example:443 HTTP/1.1\r\nHost: origin.example:443\r\n\r\n").await.unwrap();
+        let mut response = [0u8;19];
+        client_peer.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n\r\n");
+
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut forwarded = [0u8;4];
+        target_peer.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!(&forwarded, b"ping");
+        drop((client_peer,target_peer));
+
+        let stats = tunnel.await.unwrap().unwrap();
+        assert_eq!(stats.result, EstablishTunnelResult::Ok);
+    }
+
+    #[cfg(feature = "plain_text")]
+    #[tokio::test]
+    async fn test_nugget_is_forwarded() {
+        let (client,mut client_peer) = duplex(1024);
+        let (target,mut target_peer) = duplex(1024);
+        // the target comes from the Host header, whatever its case
+        let tunnel = http_tunnel(DuplexConnector { target: "origin.example:80", stream: Some(target) }, client);
+        let tunnel = tokio::spawn(tunnel.start());
+
+        let request = b"GET http://origin.example/ HTTP/1.1\r\nHOST: origin.example\r\n\r\n";
+        client_peer.write_all(request).await.unwrap();
+        let mut forwarded = vec![0u8;request.len()];
+        target_peer.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, request);
+
+        target_peer.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        drop(target_peer);
+        let mut response = Vec::new();
+        client_peer.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"HTTP/1.1 204 No Content\r\n\r\n");
+        drop(client_peer);
+
+        let stats = tunnel.await.unwrap().unwrap();
+        assert_eq!(stats.result, EstablishTunnelResult::OkWithNugget);
+        assert_eq!(stats.upstream_stats.unwrap().total_bytes, request.len());
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use log::{debug, error};
+use tokio::{io, task::spawn_blocking};
+
+use crate::{
+    configuration::{TlsIdentity, UpstreamTlsConfig},
+    parent_proxy::host_of,
+    proxy_target::TargetStream,
+    tls_client::{self, Connector},
+    tunnel::TunnelCtx,
+};
+
+// CA bundle, client certificate and key files
+type ConnectorKey = (Option<String>,Option<String>,Option<String>);
+
+// connectors by the files they were built from, shared by all the configs naming them
+static CONNECTORS: LazyLock<Mutex<HashMap<ConnectorKey,Connector>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Runs the TLS handshake with `target` over `stream`, verifying its certificate.
+/// Anything already sent on `stream`, like a PROXY header, stays in the clear.
+pub async fn wrap(stream:TargetStream, config:&UpstreamTlsConfig, target:&str, ctx:&TunnelCtx) -> io::Result<TargetStream> {
+    let server_name = config.server_name.as_deref().unwrap_or_else(|| host_of(target));
+    let connector = cached_connector(config).await?;
+    let stream = tls_client::connect(&connector, server_name, stream)
+        .await
+        .map_err(|e| {
+            error!("TLS handshake with {} as {} failed: {}, CTX={}", target, server_name, e, ctx);
+            io::Error::from(io::ErrorKind::ConnectionAborted)
+        })?;
+
+    debug!("TLS established with {} as {}, CTX={}", target, server_name, ctx);
+    Ok(TargetStream::UpstreamTls(Box::new(stream)))
+}
+
+/// The connector for `config`, its files are read and parsed on first use only,
+/// away from the runtime threads. Failures aren't kept, the next connection tries again.
+async fn cached_connector(config:&UpstreamTlsConfig) -> io::Result<Connector> {
+    let key = (config.ca_file.clone(), config.client_cert_file.clone(), config.client_key_file.clone());
+    if let Some(connector) = CONNECTORS.lock().expect("Bug: TLS connectors lock poisoned").get(&key) {
+        return Ok(connector.clone());
+    }
+
+    let config = config.clone();
+    let connector = spawn_blocking(move || connector(&config))
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))??;
+    CONNECTORS.lock().expect("Bug: TLS connectors lock poisoned").insert(key, connector.clone());
+    Ok(connector)
+}
+
+fn connector(config:&UpstreamTlsConfig) -> io::Result<Connector> {
+    let ca_bundle = config.ca_file.as_deref().map(read_pem).transpose()?;
+    let identity = match (&config.client_cert_file,&config.client_key_file) {
+        (Some(cert_file),Some(key_file)) => Some(TlsIdentity {
+            chain: read_pem(cert_file)?,
+            key: read_pem(key_file)?,
+        }),
+        (None,None) => None,
+        _ => {
+            error!("Upstream TLS needs both a client certificate and a key, or neither");
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+    };
+    tls_client::connector(ca_bundle.as_deref(), identity.as_ref())
+}
+
+fn read_pem(file_path:&str) -> io::Result<String> {
+    std::fs::read_to_string(file_path).map_err(|e| {
+        error!("Failed to read {}: {}", file_path, e);
+        e
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_certs::self_signed, test_support::temp_file};
+    use native_tls::Identity;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn test_originates_tls() {
+        let (cert,key) = self_signed("legacy.internal");
+        let (other_ca,_) = self_signed("other.internal");
+        let identity = Identity::from_pkcs8(&cert, &key).unwrap();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream,_) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let mut ping = [0u8;4];
+                        stream.read_exact(&mut ping).await.unwrap();
+                        stream.write_all(&ping).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let bundle = temp_file(&[other_ca, cert].concat());
+        let config = UpstreamTlsConfig {
+            server_name: Some("legacy.internal".to_string()),
+            ca_file: Some(bundle.path().to_str().unwrap().to_string()),
+            client_cert_file: None,
+            client_key_file: None,
+        };
+        let connect = || async { TargetStream::Tcp(TcpStream::connect(addr).await.unwrap()) };
+
+        let mut stream = wrap(connect().await, &config, &addr.to_string(), &TunnelCtx::default()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8;4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+
+        // the name has to match the certificate
+        let mismatch = UpstreamTlsConfig { server_name: Some("other.internal".to_string()), ..config.clone() };
+        let result = wrap(connect().await, &mismatch, &addr.to_string(), &TunnelCtx::default()).await;
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::ConnectionAborted));
+
+        let half_identity = UpstreamTlsConfig { client_cert_file: config.ca_file.clone(), ..config.clone() };
+        let result = wrap(connect().await, &half_identity, &addr.to_string(), &TunnelCtx::default()).await;
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+
+        // the bundle was read for the first connection, not again
+        drop(bundle);
+        let stream = wrap(connect().await, &config, &addr.to_string(), &TunnelCtx::default()).await;
+        assert!(stream.is_ok());
+    }
+}